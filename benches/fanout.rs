@@ -0,0 +1,74 @@
+//! Cost of fanning a published message out to many subscribers.
+//!
+//! Each benchmark calls `Registry::send` directly, with every subscriber
+//! on a socket whose event loop discards what it is sent. Every
+//! subscriber is queued its own copy of the message (see
+//! `Subscriber::deliver`), so the bytes reported are the bytes copied
+//! and these benchmarks are the baseline for sharing them instead. Run
+//! with `make bench`.
+
+#![feature(test)]
+
+extern crate test;
+extern crate unicorn;
+extern crate ws;
+
+use std::thread;
+
+use test::Bencher;
+use unicorn::network::encoding::Encoding;
+use unicorn::router::{PublishOptions, Registry, SubscribeOptions, Subscriber};
+
+/// Sender whose messages are consumed by a running, connection-less
+/// event loop. Its queue is made large enough that a burst of sends
+/// never overflows it, which would drop messages.
+fn sink() -> ws::Sender {
+    let settings = ws::Settings {
+        max_connections: 1,
+        queue_size: 16 * 1024,
+        ..ws::Settings::default()
+    };
+    let socket = ws::Builder::new()
+        .with_settings(settings)
+        .build(|_| |_| Ok(()))
+        .unwrap();
+    let sender = socket.broadcaster();
+    thread::spawn(move || socket.listen("127.0.0.1:0"));
+    sender
+}
+
+fn fan_out(b: &mut Bencher, subscribers: usize, size: usize) {
+    let sender = sink();
+    let mut reg = Registry::new();
+    for i in 0..subscribers {
+        reg.subscribe("bench".to_string(),
+                      format!("s{}", i),
+                      Subscriber::new(i as u64, sender.clone(), Encoding::Json),
+                      SubscribeOptions::default())
+            .unwrap();
+    }
+
+    let payload: String = (0..size).map(|_| 'x').collect();
+    b.bytes = (subscribers * size) as u64;
+    b.iter(|| {
+        reg.send("bench",
+                 Some("p"),
+                 ws::Message::text(payload.clone()),
+                 PublishOptions::default());
+    });
+}
+
+#[bench]
+fn send_1kb_to_100_subscribers(b: &mut Bencher) {
+    fan_out(b, 100, 1024);
+}
+
+#[bench]
+fn send_1kb_to_1000_subscribers(b: &mut Bencher) {
+    fan_out(b, 1000, 1024);
+}
+
+#[bench]
+fn send_1mb_to_100_subscribers(b: &mut Bencher) {
+    fan_out(b, 100, 1024 * 1024);
+}
@@ -0,0 +1,101 @@
+//! Cost of answering `topic.publish` calls over WebSocket connections.
+//!
+//! Every benchmark makes the same total number of calls, spread over a
+//! different number of connections to a listening socket, and waits for
+//! all of their responses. A socket handles the calls of all of its
+//! connections on its one event loop thread, so more connections do not
+//! spread the work over more cores; they show the cost of taking turns
+//! between them. Run with `make bench`.
+
+#![feature(test)]
+
+extern crate test;
+extern crate unicorn;
+extern crate ws;
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, channel};
+use std::thread;
+use std::time::Duration;
+
+use test::Bencher;
+use unicorn::api::topic::TopicAPI;
+use unicorn::network::websocket::WebSocket;
+use unicorn::router::Router;
+use unicorn::schema::config_schema::RouterConfig;
+use unicorn::worker::Workers;
+
+/// Calls made per benchmark iteration. Kept below what the event loop
+/// of a client connection can queue at once.
+const PUBLISHES: usize = 400;
+
+const REQUEST: &'static str = r#"{"jsonrpc": "2.0", "id": 1, "method": "topic.publish",
+    "params": {"topic_id": "bench", "message": "hello"}}"#;
+
+/// Address of a new socket serving `topic.publish`
+fn serve() -> String {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = format!("127.0.0.1:{}", port);
+    let listen = addr.clone();
+    thread::spawn(move || {
+        let router = Router::spawn(RouterConfig::default(), None);
+        let mut socket = WebSocket::new();
+        let api = TopicAPI::with_router(router, Workers::default()).set_type("publish");
+        socket.add_method("topic.publish", api);
+        socket.listen(&listen)
+    });
+    while TcpStream::connect(&addr[..]).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    addr
+}
+
+/// Open a client connection to `addr`, each on its own event loop.
+/// Returns its sender; every response it gets is signalled on `answered`.
+fn connect(addr: &str, answered: mpsc::Sender<()>) -> ws::Sender {
+    let (opened, sender) = channel();
+    let url = format!("ws://{}", addr);
+    thread::spawn(move || {
+        ws::connect(url, |out| {
+            let _ = opened.send(out);
+            let answered = answered.clone();
+            move |_| {
+                let _ = answered.send(());
+                Ok(())
+            }
+        })
+    });
+    sender.recv().unwrap()
+}
+
+fn publish(b: &mut Bencher, connections: usize) {
+    let addr = serve();
+    let (answered, responses) = channel();
+    let clients: Vec<ws::Sender> = (0..connections)
+        .map(|_| connect(&addr, answered.clone()))
+        .collect();
+
+    b.iter(|| {
+        for i in 0..PUBLISHES {
+            clients[i % connections].send(REQUEST).unwrap();
+        }
+        for _ in 0..PUBLISHES {
+            responses.recv().unwrap();
+        }
+    });
+}
+
+#[bench]
+fn publish_1_connection(b: &mut Bencher) {
+    publish(b, 1);
+}
+
+#[bench]
+fn publish_4_connections(b: &mut Bencher) {
+    publish(b, 4);
+}
+
+#[bench]
+fn publish_16_connections(b: &mut Bencher) {
+    publish(b, 16);
+}
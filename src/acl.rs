@@ -0,0 +1,257 @@
+//! Access control for topics.
+//!
+//! When enabled, connections may only publish to, subscribe to, create
+//! or delete the topics some rule grants them the right to:
+//!
+//! - `publish` covers `topic.publish` and `topic.request`, and replying
+//!   to a request by publishing to its inbox
+//! - `subscribe` covers `topic.subscribe`, `topic.ack` and `topic.info`;
+//!   `topic.list` only lists the topics it holds on
+//! - `create` covers `topic.create` and `topic.delete`
+//!
+//! A rule applies to a single identity, to the connections whose token
+//! gives them a role, or to every connection. Its rights hold on the
+//! topics matched by its topic id or pattern; subscribing to a pattern
+//! needs a rule matching every topic the pattern does.
+//!
+//! Rules name identities as `Identity::name` does.
+//!
+//! Rules start out as configured. Admins can grant and revoke rules
+//! while the server runs; such changes last until it restarts.
+
+use std::sync::{Arc, RwLock};
+
+use network::websocket::{Identity, IdentitySource};
+use router::{covers_pattern, is_valid_pattern};
+use schema::config_schema::{AclConfig, AclRule};
+
+/// What a rule can allow on a topic
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Right {
+    Publish,
+    Subscribe,
+    Create,
+}
+
+impl Right {
+    /// Right named in the `allow` of a rule
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "publish" => Some(Right::Publish),
+            "subscribe" => Some(Right::Subscribe),
+            "create" => Some(Right::Create),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Right::Publish => "publish",
+            Right::Subscribe => "subscribe",
+            Right::Create => "create",
+        }
+    }
+}
+
+/// Check if `name` names an identity, as `account:{id}` or
+/// `token:{subject}`
+fn is_identity_name(name: &str) -> bool {
+    [IdentitySource::Account, IdentitySource::Token]
+        .iter()
+        .any(|s| name.starts_with(s.prefix()) && name.len() > s.prefix().len())
+}
+
+/// Check if `rule` is well formed
+pub fn validate(rule: &AclRule) -> Result<(), &'static str> {
+    if rule.topic.is_empty() || !is_valid_pattern(&rule.topic) {
+        return Err("invalid topic pattern");
+    }
+    if rule.identity.as_ref().map_or(false, |i| !is_identity_name(i)) {
+        return Err("identity is not named `account:{id}` or `token:{subject}`");
+    }
+    if rule.allow.is_empty() {
+        return Err("no rights granted");
+    }
+    if rule.allow.iter().any(|r| Right::from_name(r).is_none()) {
+        return Err("unknown right");
+    }
+    Ok(())
+}
+
+/// Check if `identity` has `role`, as given by the `role` or `roles`
+/// claim of its token
+fn has_role(identity: &Identity, role: &str) -> bool {
+    let claims = match identity.claims.as_object() {
+        Some(c) => c,
+        None => return false,
+    };
+    if claims.get("role").and_then(|r| r.as_str()) == Some(role) {
+        return true;
+    }
+    claims.get("roles")
+        .and_then(|r| r.as_array())
+        .map_or(false, |rs| rs.iter().any(|r| r.as_str() == Some(role)))
+}
+
+/// Check if `rule` applies to a connection authenticated as `identity`
+fn applies(rule: &AclRule, identity: Option<&Identity>) -> bool {
+    let named = match rule.identity {
+        Some(ref n) => identity.map_or(false, |i| i.name() == *n),
+        None => true,
+    };
+    let role = match rule.role {
+        Some(ref r) => identity.map_or(false, |i| has_role(i, r)),
+        None => true,
+    };
+    named && role
+}
+
+/// Rules in force, shared by every connection
+#[derive(Clone, Default)]
+pub struct Acl {
+    enabled: bool,
+    admins: Arc<Vec<String>>,
+    rules: Arc<RwLock<Vec<AclRule>>>,
+}
+
+impl Acl {
+    /// Rules as configured in `conf`. Fails on the first malformed rule
+    /// or admin name.
+    pub fn new(conf: &AclConfig) -> Result<Self, String> {
+        if let Some(a) = conf.admins.iter().find(|a| !is_identity_name(a)) {
+            return Err(format!("admin {:?} is not named `account:{{id}}` or `token:{{subject}}`",
+                               a));
+        }
+        for (i, rule) in conf.rules.iter().enumerate() {
+            try!(validate(rule).map_err(|e| format!("rule {} on {:?}: {}", i, rule.topic, e)));
+        }
+        Ok(Acl {
+            enabled: conf.enabled,
+            admins: Arc::new(conf.admins.clone()),
+            rules: Arc::new(RwLock::new(conf.rules.clone())),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check if a connection authenticated as `identity`, if at all, has
+    /// `right` on every topic matched by `topic`
+    pub fn allows(&self, identity: Option<&Identity>, right: Right, topic: &str) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let rules = match self.rules.read() {
+            Ok(r) => r,
+            Err(p) => p.into_inner(),
+        };
+        rules.iter().any(|r| {
+            applies(r, identity) && r.allow.iter().any(|a| a == right.name()) &&
+            covers_pattern(&r.topic, topic)
+        })
+    }
+
+    /// Check if a connection authenticated as `identity` may change the
+    /// rules
+    pub fn is_admin(&self, identity: Option<&Identity>) -> bool {
+        identity.map_or(false, |i| self.admins.contains(&i.name()))
+    }
+
+    /// Rules in force, in the order they were granted
+    pub fn rules(&self) -> Vec<AclRule> {
+        match self.rules.read() {
+            Ok(r) => r.clone(),
+            Err(p) => p.into_inner().clone(),
+        }
+    }
+
+    /// Add `rule`, unless it is already in force. Returns whether it was
+    /// added.
+    pub fn grant(&self, rule: &AclRule) -> bool {
+        let mut rules = match self.rules.write() {
+            Ok(r) => r,
+            Err(p) => p.into_inner(),
+        };
+        if rules.contains(rule) {
+            return false;
+        }
+        rules.push(rule.clone());
+        true
+    }
+
+    /// Remove `rule`. Returns whether it was in force.
+    pub fn revoke(&self, rule: &AclRule) -> bool {
+        let mut rules = match self.rules.write() {
+            Ok(r) => r,
+            Err(p) => p.into_inner(),
+        };
+        let before = rules.len();
+        rules.retain(|r| r != rule);
+        rules.len() != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{self, Value};
+
+    fn rule(topic: &str, identity: Option<&str>, role: Option<&str>, allow: &[&str]) -> AclRule {
+        AclRule {
+            topic: topic.to_string(),
+            identity: identity.map(|i| i.to_string()),
+            role: role.map(|r| r.to_string()),
+            allow: allow.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn enabled(rules: Vec<AclRule>) -> Acl {
+        let conf = AclConfig {
+            enabled: true,
+            admins: Vec::new(),
+            rules: rules,
+        };
+        Acl::new(&conf).unwrap()
+    }
+
+    #[test]
+    fn denies_by_default_once_enabled() {
+        let acl = enabled(Vec::new());
+        let alice = Identity::account("alice".to_string());
+        assert!(!acl.allows(None, Right::Publish, "t"));
+        assert!(!acl.allows(Some(&alice), Right::Subscribe, "t"));
+        assert!(Acl::default().allows(None, Right::Create, "t"));
+    }
+
+    #[test]
+    fn applies_rules_to_identities_roles_and_everyone() {
+        let acl = enabled(vec![rule("public/#", None, None, &["subscribe"]),
+                               rule("ops/#", None, Some("ops"), &["publish", "subscribe"]),
+                               rule("alice/+", Some("account:alice"), None, &["create"])]);
+        let alice = Identity::account("alice".to_string());
+        let alice_token = Identity::token("alice".to_string(), Value::Null);
+        let claims: Value = serde_json::from_str(r#"{"roles": ["dev", "ops"]}"#).unwrap();
+        let bob = Identity::token("bob".to_string(), claims);
+
+        // Everyone, authenticated or not
+        assert!(acl.allows(None, Right::Subscribe, "public/news"));
+        assert!(acl.allows(Some(&alice), Right::Subscribe, "public/news"));
+        assert!(!acl.allows(None, Right::Publish, "public/news"));
+
+        // Role
+        assert!(acl.allows(Some(&bob), Right::Publish, "ops/alerts"));
+        assert!(!acl.allows(Some(&alice), Right::Subscribe, "ops/alerts"));
+        assert!(!acl.allows(None, Right::Subscribe, "ops/alerts"));
+
+        // Identity, which a token with the same subject does not pass for
+        assert!(acl.allows(Some(&alice), Right::Create, "alice/inbox"));
+        assert!(!acl.allows(Some(&alice_token), Right::Create, "alice/inbox"));
+        assert!(!acl.allows(Some(&bob), Right::Create, "alice/inbox"));
+
+        // Patterns need a rule matching every topic they do
+        assert!(acl.allows(Some(&bob), Right::Subscribe, "ops/+"));
+        assert!(!acl.allows(Some(&bob), Right::Subscribe, "#"));
+    }
+}
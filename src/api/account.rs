@@ -0,0 +1,243 @@
+//! Account API
+//!
+//! Accounts are kept in a `DataStore` under `account:{id}`, with their
+//! password as a salted Argon2i hash. Logging in authenticates the
+//! connection as the account, for the calls that follow on it.
+//!
+//! Hashing is slow on purpose, so calls are answered from a pool of their
+//! own and not from the event loop, and are refused as `Overloaded` while
+//! that pool has too many waiting. Calls made on a connection before the
+//! answer to its login may not see it.
+//!
+//! Ids starting with `_` are reserved for the server. Accounts that the
+//! `Acl` names as admins can only be registered by an admin.
+
+use std::sync::{Arc, Mutex};
+
+use argon2rs::verifier::Encoded;
+use rand::{OsRng, Rng};
+use serde_json::{self, Value, from_value, to_value};
+
+use acl::Acl;
+use datastore::DataStore;
+use network::websocket::{APIHandlerCommand, Connection, Identity, IdentitySource, Responder,
+                         WebSocket};
+use schema::account_schema::{Account, AccountInfo, AccountLogin, AccountPasswordChange,
+                             NewAccount, account_key};
+use schema::message_schema::ResponseError;
+use util::now_millis;
+use worker::{RunError, Workers};
+use super::store_error;
+
+/// Bytes of random salt hashed with each password
+const SALT_LEN: usize = 16;
+
+/// Prefix of the account ids reserved for the server
+const RESERVED_PREFIX: &'static str = "_";
+
+#[derive(Clone)]
+enum ActionType {
+    Register,
+    Login,
+    ChangePassword,
+}
+
+#[derive(Clone)]
+pub struct AccountAPI {
+    store: Arc<DataStore>,
+    /// Held while registering, so that two connections cannot both
+    /// create the same account
+    registering: Arc<Mutex<()>>,
+    acl: Acl,
+    hashers: Workers,
+    actiontype: Option<ActionType>,
+}
+
+impl AccountAPI {
+    /// Account methods on `store`, hashing passwords on `hashers`
+    pub fn with_store(store: Arc<DataStore>, hashers: Workers) -> Self {
+        AccountAPI {
+            store: store,
+            registering: Arc::new(Mutex::new(())),
+            acl: Acl::default(),
+            hashers: hashers,
+            actiontype: None,
+        }
+    }
+
+    /// Keep the accounts `acl` names as admins for admins to register
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    pub fn set_type(mut self, t: &str) -> Self {
+        self.actiontype = match t {
+            "register" => Some(ActionType::Register),
+            "login" => Some(ActionType::Login),
+            "change_password" => Some(ActionType::ChangePassword),
+            _ => None,
+        };
+        self
+    }
+
+    /// Add the account methods to `socket`
+    pub fn register(&self, socket: &mut WebSocket) {
+        socket.add_method("account.register", self.clone().set_type("register"));
+        socket.add_method("account.login", self.clone().set_type("login"));
+        socket.add_method("account.change_password",
+                          self.clone().set_type("change_password"));
+    }
+
+    fn load(&self, id: &str) -> Result<Option<Account>, ResponseError> {
+        match self.store.get(&account_key(id)) {
+            Ok(Some(v)) => {
+                serde_json::from_slice::<Account>(&v).map(Some).map_err(|e| {
+                    error!("[account] Unable to read account {}: {}", id, e);
+                    ResponseError::InternalError
+                })
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    fn save(&self, account: &Account) -> Result<(), ResponseError> {
+        let v = try!(serde_json::to_vec(account).map_err(|e| {
+            error!("[account] Unable to write account {}: {}", account.id, e);
+            ResponseError::InternalError
+        }));
+        self.store.put(&account_key(&account.id), v, None).map_err(store_error)
+    }
+
+    /// The account `id`, if `password` is its password
+    fn check(&self, id: &str, password: &str) -> Result<Account, ResponseError> {
+        match try!(self.load(id)) {
+            Some(a) => {
+                if verify_password(&a.password_hash, password) {
+                    Ok(a)
+                } else {
+                    Err(ResponseError::Unauthorized)
+                }
+            }
+            None => {
+                // Take as long as for a wrong password, so that response
+                // times do not tell which accounts exist
+                let _ = hash_password(password);
+                Err(ResponseError::Unauthorized)
+            }
+        }
+    }
+
+    /// Register the account in `params`
+    fn create(&self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
+        let p = try!(from_value::<NewAccount>(params)
+            .map_err(|_| ResponseError::InvalidPayload));
+        if p.id.is_empty() || p.password.is_empty() || p.id.starts_with(RESERVED_PREFIX) {
+            return Err(ResponseError::InvalidPayload);
+        }
+        if self.acl.is_admin(Some(&Identity::account(p.id.clone()))) &&
+           !self.acl.is_admin(conn.identity().as_ref()) {
+            debug!("[account] Connection {} may not register admin {}", conn.id, p.id);
+            return Err(ResponseError::Unauthorized);
+        }
+        let account = Account {
+            id: p.id.clone(),
+            password_hash: try!(hash_password(&p.password)),
+            created_ms: now_millis(),
+        };
+
+        let _guard = self.registering.lock().unwrap_or_else(|p| p.into_inner());
+        if try!(self.load(&p.id)).is_some() {
+            return Err(ResponseError::Conflict);
+        }
+        try!(self.save(&account));
+        debug!("[account] Registered {}", account.id);
+        Ok(to_value(&AccountInfo {
+            id: account.id,
+            created_ms: account.created_ms,
+        }))
+    }
+
+    /// Authenticate `conn` as the account in `params`
+    fn login(&self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
+        let p = try!(from_value::<AccountLogin>(params)
+            .map_err(|_| ResponseError::InvalidPayload));
+        let account = try!(self.check(&p.id, &p.password));
+        conn.authenticate(Identity::account(account.id.clone()));
+        debug!("[account] Connection {} logged in as {}", conn.id, account.id);
+        Ok(to_value(&AccountInfo {
+            id: account.id,
+            created_ms: account.created_ms,
+        }))
+    }
+
+    /// Change the password of the account `conn` is logged in to
+    fn change_password(&self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
+        let p = try!(from_value::<AccountPasswordChange>(params)
+            .map_err(|_| ResponseError::InvalidPayload));
+        if p.new_password.is_empty() {
+            return Err(ResponseError::InvalidPayload);
+        }
+        let own = conn.identity()
+            .map_or(false, |i| i.source == IdentitySource::Account && i.id == p.id);
+        if !own {
+            return Err(ResponseError::Unauthorized);
+        }
+        let mut account = try!(self.check(&p.id, &p.password));
+        account.password_hash = try!(hash_password(&p.new_password));
+        try!(self.save(&account));
+        debug!("[account] Changed password of {}", account.id);
+        Ok(Value::Null)
+    }
+}
+
+/// Encoded Argon2i hash of `password` with a fresh random salt
+fn hash_password(password: &str) -> Result<String, ResponseError> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut rng = try!(OsRng::new().map_err(|e| {
+        error!("[account] Unable to get random salt: {}", e);
+        ResponseError::InternalError
+    }));
+    rng.fill_bytes(&mut salt);
+    let encoded = Encoded::default2i(password.as_bytes(), &salt, &[], &[]);
+    String::from_utf8(encoded.to_u8()).map_err(|_| ResponseError::InternalError)
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    Encoded::from_u8(hash.as_bytes()).map(|e| e.verify(password.as_bytes())).unwrap_or(false)
+}
+
+impl APIHandlerCommand for AccountAPI {
+    fn execute(&mut self, _: &Connection, _: Value) -> Result<Value, ResponseError> {
+        match self.actiontype {
+            // Answered through `execute_deferred`
+            Some(_) => Err(ResponseError::InvalidRequest),
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+
+    fn is_deferred(&self) -> bool {
+        self.actiontype.is_some()
+    }
+
+    fn execute_deferred(&mut self,
+                        conn: &Connection,
+                        params: Value,
+                        reply: Responder)
+                        -> Result<(), ResponseError> {
+        let (api, id, conn) = (self.clone(), conn.id, conn.clone());
+        let run = self.hashers.run(move || {
+            reply.respond(match api.actiontype {
+                Some(ActionType::Register) => api.create(&conn, params),
+                Some(ActionType::Login) => api.login(&conn, params),
+                Some(ActionType::ChangePassword) => api.change_password(&conn, params),
+                None => Err(ResponseError::MethodNotFound),
+            })
+        });
+        if run == Err(RunError::Full) {
+            debug!("[account] Too many calls waiting to hash, refusing one from {}", id);
+        }
+        run.map_err(ResponseError::from)
+    }
+}
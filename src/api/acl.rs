@@ -0,0 +1,100 @@
+//! Access control API
+//!
+//! Lets the admins named in the configuration list, grant and revoke
+//! the rules of the `Acl`. Changes are only kept in memory: once the
+//! server restarts, the rules are back to those of the configuration.
+
+use serde_json::{Value, from_value, to_value};
+
+use acl::{self, Acl};
+use network::websocket::{APIHandlerCommand, Connection, WebSocket};
+use schema::config_schema::AclRule;
+use schema::message_schema::ResponseError;
+
+#[derive(Clone)]
+enum ActionType {
+    List,
+    Grant,
+    Revoke,
+}
+
+#[derive(Clone)]
+pub struct AclAPI {
+    acl: Acl,
+    actiontype: Option<ActionType>,
+}
+
+impl AclAPI {
+    pub fn with_acl(acl: Acl) -> Self {
+        AclAPI {
+            acl: acl,
+            actiontype: None,
+        }
+    }
+
+    pub fn set_type(mut self, t: &str) -> Self {
+        self.actiontype = match t {
+            "list" => Some(ActionType::List),
+            "grant" => Some(ActionType::Grant),
+            "revoke" => Some(ActionType::Revoke),
+            _ => None,
+        };
+        self
+    }
+
+    /// Add the access control methods to `socket`
+    pub fn register(&self, socket: &mut WebSocket) {
+        socket.add_method("acl.list", self.clone().set_type("list"));
+        socket.add_method("acl.grant", self.clone().set_type("grant"));
+        socket.add_method("acl.revoke", self.clone().set_type("revoke"));
+    }
+
+    fn rule(params: Value) -> Result<AclRule, ResponseError> {
+        let rule = try!(from_value::<AclRule>(params).map_err(|_| ResponseError::InvalidPayload));
+        try!(acl::validate(&rule).map_err(|e| {
+            debug!("[acl] Invalid rule on {:?}: {}", rule.topic, e);
+            ResponseError::InvalidPayload
+        }));
+        Ok(rule)
+    }
+}
+
+impl APIHandlerCommand for AclAPI {
+    fn execute(&mut self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
+        if self.actiontype.is_none() {
+            return Err(ResponseError::MethodNotFound);
+        }
+        let identity = conn.identity();
+        if !self.acl.is_admin(identity.as_ref()) {
+            return Err(ResponseError::Unauthorized);
+        }
+        match self.actiontype {
+            Some(ActionType::List) => Ok(to_value(&self.acl.rules())),
+            Some(ActionType::Grant) => {
+                let rule = try!(AclAPI::rule(params));
+                if self.acl.grant(&rule) {
+                    info!("[acl] Connection {} granted {:?} on {}",
+                          conn.id,
+                          rule.allow,
+                          rule.topic);
+                    Ok(Value::Null)
+                } else {
+                    Err(ResponseError::Conflict)
+                }
+            }
+            Some(ActionType::Revoke) => {
+                let rule = try!(AclAPI::rule(params));
+                if self.acl.revoke(&rule) {
+                    info!("[acl] Connection {} revoked {:?} on {}",
+                          conn.id,
+                          rule.allow,
+                          rule.topic);
+                    Ok(Value::Null)
+                } else {
+                    Err(ResponseError::NotFound)
+                }
+            }
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+}
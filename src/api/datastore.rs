@@ -0,0 +1,198 @@
+//! DataStore API
+//!
+//! The store holds raw records, with no rights of their own. Once the
+//! `Acl` is enabled, only admins may use it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Value, from_value, to_value};
+
+use acl::Acl;
+use datastore::DataStore;
+use network::websocket::{APIHandlerCommand, Connection, WebSocket};
+use schema::message_schema::ResponseError;
+use schema::datastore_schema::{DataKey, DataPut, DataRecord, DataScan};
+use super::store_error;
+
+#[derive(Clone)]
+enum ActionType {
+    Get,
+    Put,
+    Delete,
+    Scan,
+}
+
+#[derive(Clone)]
+pub struct DataStoreAPI {
+    store: Arc<DataStore>,
+    acl: Acl,
+    actiontype: Option<ActionType>,
+}
+
+impl DataStoreAPI {
+    pub fn with_store(store: Arc<DataStore>) -> Self {
+        DataStoreAPI {
+            store: store,
+            acl: Acl::default(),
+            actiontype: None,
+        }
+    }
+
+    /// Serve only the admins of `acl`, if enabled
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    pub fn set_type(mut self, t: &str) -> Self {
+        self.actiontype = match t {
+            "get" => Some(ActionType::Get),
+            "put" => Some(ActionType::Put),
+            "delete" => Some(ActionType::Delete),
+            "scan" => Some(ActionType::Scan),
+            _ => None,
+        };
+        self
+    }
+
+    /// Add the datastore methods to `socket`
+    pub fn register(&self, socket: &mut WebSocket) {
+        socket.add_method("datastore.get", self.clone().set_type("get"));
+        socket.add_method("datastore.put", self.clone().set_type("put"));
+        socket.add_method("datastore.delete", self.clone().set_type("delete"));
+        socket.add_method("datastore.scan", self.clone().set_type("scan"));
+    }
+}
+
+impl APIHandlerCommand for DataStoreAPI {
+    fn execute(&mut self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
+        if self.acl.is_enabled() && !self.acl.is_admin(conn.identity().as_ref()) {
+            debug!("[datastore] Connection {} is not an admin", conn.id);
+            return Err(ResponseError::Unauthorized);
+        }
+        match self.actiontype {
+            Some(ActionType::Get) => {
+                let p = try!(from_value::<DataKey>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                match self.store.get(&p.key) {
+                    Ok(Some(v)) => Ok(Value::String(String::from_utf8_lossy(&v).into_owned())),
+                    Ok(None) => Err(ResponseError::NotFound),
+                    Err(e) => Err(store_error(e)),
+                }
+            }
+            Some(ActionType::Put) => {
+                let p = try!(from_value::<DataPut>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                let ttl = p.ttl_ms.map(Duration::from_millis);
+                match self.store.put(&p.key, p.value.into_bytes(), ttl) {
+                    Ok(()) => Ok(Value::Null),
+                    Err(e) => Err(store_error(e)),
+                }
+            }
+            Some(ActionType::Delete) => {
+                let p = try!(from_value::<DataKey>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                match self.store.delete(&p.key) {
+                    Ok(true) => Ok(Value::Null),
+                    Ok(false) => Err(ResponseError::NotFound),
+                    Err(e) => Err(store_error(e)),
+                }
+            }
+            Some(ActionType::Scan) => {
+                let p = try!(from_value::<DataScan>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                let records: Vec<DataRecord> = try!(self.store.scan(&p.prefix).map_err(store_error))
+                    .into_iter()
+                    .map(|(k, v)| {
+                        DataRecord {
+                            key: k,
+                            value: String::from_utf8_lossy(&v).into_owned(),
+                        }
+                    })
+                    .collect();
+                Ok(to_value(&records))
+            }
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+    use ws;
+
+    use datastore::MemoryStore;
+    use network::websocket::Identity;
+    use schema::config_schema::AclConfig;
+
+    fn connection() -> Connection {
+        Connection::new(1, ws::WebSocket::new(|_| |_| Ok(())).unwrap().broadcaster())
+    }
+
+    fn call(api: &DataStoreAPI,
+            action: &str,
+            conn: &Connection,
+            params: &str)
+            -> Result<Value, ResponseError> {
+        let params = serde_json::from_str(params).unwrap();
+        api.clone().set_type(action).execute(conn, params)
+    }
+
+    #[test]
+    fn gets_puts_deletes_and_scans() {
+        let api = DataStoreAPI::with_store(Arc::new(MemoryStore::new()));
+        let conn = connection();
+        assert_eq!(call(&api, "get", &conn, r#"{"key": "a/1"}"#).err(),
+                   Some(ResponseError::NotFound));
+        for &(k, v) in &[("a/2", "two"), ("a/1", "one"), ("b/1", "other")] {
+            let p = format!(r#"{{"key": "{}", "value": "{}"}}"#, k, v);
+            assert_eq!(call(&api, "put", &conn, &p), Ok(Value::Null));
+        }
+        assert_eq!(call(&api, "get", &conn, r#"{"key": "a/1"}"#),
+                   Ok(Value::String("one".to_string())));
+
+        let scanned = call(&api, "scan", &conn, r#"{"prefix": "a/"}"#).unwrap();
+        let expected: Value = serde_json::from_str(r#"[{"key": "a/1", "value": "one"},
+                                                       {"key": "a/2", "value": "two"}]"#)
+            .unwrap();
+        assert_eq!(scanned, expected);
+
+        assert_eq!(call(&api, "delete", &conn, r#"{"key": "a/1"}"#), Ok(Value::Null));
+        assert_eq!(call(&api, "delete", &conn, r#"{"key": "a/1"}"#).err(),
+                   Some(ResponseError::NotFound));
+        assert_eq!(call(&api, "get", &conn, r#"{"key": "a/1"}"#).err(),
+                   Some(ResponseError::NotFound));
+    }
+
+    #[test]
+    fn refuses_invalid_params() {
+        let api = DataStoreAPI::with_store(Arc::new(MemoryStore::new()));
+        let conn = connection();
+        assert_eq!(call(&api, "put", &conn, r#"{"key": "a"}"#).err(),
+                   Some(ResponseError::InvalidPayload));
+        assert_eq!(call(&api, "nothing", &conn, r#"{"key": "a"}"#).err(),
+                   Some(ResponseError::MethodNotFound));
+    }
+
+    #[test]
+    fn serves_only_admins_once_the_acl_is_enabled() {
+        let conf = AclConfig {
+            enabled: true,
+            admins: vec!["account:root".to_string()],
+            rules: Vec::new(),
+        };
+        let api = DataStoreAPI::with_store(Arc::new(MemoryStore::new()))
+            .with_acl(Acl::new(&conf).unwrap());
+        let conn = connection();
+        let get = r#"{"key": "a"}"#;
+        assert_eq!(call(&api, "get", &conn, get).err(), Some(ResponseError::Unauthorized));
+        conn.authenticate(Identity::account("someone".to_string()));
+        assert_eq!(call(&api, "get", &conn, get).err(), Some(ResponseError::Unauthorized));
+        conn.authenticate(Identity::account("root".to_string()));
+        assert_eq!(call(&api, "get", &conn, get).err(), Some(ResponseError::NotFound));
+    }
+}
@@ -1,3 +1,18 @@
 //! unicorn's API handlers
 
+pub mod account;
+pub mod acl;
+pub mod datastore;
+pub mod session;
 pub mod topic;
+
+use std::fmt::Display;
+
+use schema::message_schema::ResponseError;
+
+/// Log a failure of the store behind a call, which is answered with
+/// `StoreError`
+fn store_error<E: Display>(e: E) -> ResponseError {
+    error!("[api] Store failed: {}", e);
+    ResponseError::StoreError
+}
@@ -0,0 +1,29 @@
+//! Session API
+
+use serde_json::{Value, to_value};
+
+use network::websocket::{APIHandlerCommand, Connection, WebSocket};
+use schema::message_schema::ResponseError;
+use schema::session_schema::SessionHello;
+
+#[derive(Clone)]
+pub struct SessionAPI;
+
+impl SessionAPI {
+    /// Add the session methods to `socket`
+    pub fn register(&self, socket: &mut WebSocket) {
+        socket.add_method("session.hello", self.clone());
+    }
+}
+
+impl APIHandlerCommand for SessionAPI {
+    /// Tell the client the ids the server knows its connection by
+    fn execute(&mut self, conn: &Connection, _: Value) -> Result<Value, ResponseError> {
+        Ok(to_value(&SessionHello {
+            connection_id: conn.id,
+            session_id: conn.session_id(),
+            publisher_id: conn.publisher_id(),
+            authenticated: conn.identity().is_some(),
+        }))
+    }
+}
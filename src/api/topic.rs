@@ -1,104 +1,634 @@
 //! Topic API
+//!
+//! Calls are checked against the `Acl`, if enabled.
+//!
+//! Subscriptions are held under the session id of the connection making
+//! them, followed by the `subscriber_id` it gives if any. Messages carry
+//! the identity of the connection publishing them as their
+//! `publisher_id`, or its session id if it is not authenticated, and are
+//! not delivered to its subscription under its bare session id. Clients
+//! learn both ids from `session.hello`.
 
-use ws::Sender as WSSender;
 use ws::Message as WSMessage;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
 
-use serde_json::from_str;
+use rand::{OsRng, Rng};
+use serde_json::{Value, from_value, to_value};
 
-use network::websocket::APIHandlerCommand;
-use router::RouterCommand;
-use schema::message_schema::MessageResponse;
-use schema::message_schema::MessageRequestText;
-use schema::topic_schema::{TopicCreate, TopicPublish, TopicSubscribe};
+use acl::{Acl, Right};
+use filter::Filter;
+use network::websocket::{APIHandlerCommand, Connection, Responder, WebSocket};
+use router::{INBOX_PREFIX, is_inbox, is_pattern, is_topic_id, is_valid_pattern};
+use router::{PublishOptions, Router, RouterCommand, SubscribeOptions, Subscriber, TopicOptions,
+             Visible};
+use schema::message_schema::ResponseError;
+use schema::topic_schema::{TopicAck, TopicCreate, TopicKey, TopicList, TopicListing, TopicPublish,
+                           TopicPublishBinary, TopicRequest, TopicSubscribe};
+use worker::Workers;
+
+/// How long to wait for the router to answer a query
+const REPLY_TIMEOUT_MS: u64 = 5000;
+
+/// Topics listed when no limit is given
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Most topics listed at once
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Random bytes in the inbox and correlation ids of a request
+const REQUEST_ID_LEN: usize = 16;
+
+/// Longest ttl of a message, and longest wait for a reply: a year
+const MAX_DURATION_MS: u64 = 365 * 24 * 60 * 60 * 1000;
 
 #[derive(Clone)]
 enum ActionType {
     Create,
     Subscribe,
     Publish,
-    Unsubscribe
+    Unsubscribe,
+    Delete,
+    List,
+    Info,
+    Ack,
+    Request,
 }
 
 #[derive(Clone)]
 pub struct TopicAPI {
-    tx: Arc<Mutex<Sender<RouterCommand>>>,
+    router: Router,
+    acl: Acl,
+    workers: Workers,
     actiontype: Option<ActionType>,
 }
 
 impl TopicAPI {
-    pub fn with_tx(tx: Arc<Mutex<Sender<RouterCommand>>>) -> Self {
+    /// Topic methods on `router`, waiting for its answers on `workers`
+    pub fn with_router(router: Router, workers: Workers) -> Self {
         TopicAPI {
-            tx: tx,
+            router: router,
+            acl: Acl::default(),
+            workers: workers,
             actiontype: None,
         }
     }
 
+    /// Check calls against `acl`
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
     pub fn set_type(mut self, t: &str) -> Self {
         self.actiontype = match t {
             "create" => Some(ActionType::Create),
             "publish" => Some(ActionType::Publish),
             "subscribe" => Some(ActionType::Subscribe),
             "unsubscribe" => Some(ActionType::Unsubscribe),
+            "delete" => Some(ActionType::Delete),
+            "list" => Some(ActionType::List),
+            "info" => Some(ActionType::Info),
+            "ack" => Some(ActionType::Ack),
+            "request" => Some(ActionType::Request),
             _ => None,
         };
         self
     }
 
-    fn transmit(&self, c: RouterCommand) {
-        if let Ok(t) = self.tx.lock() {
-            let _ = t.send(c);
+    /// Add the topic methods to `socket`
+    pub fn register(&self, socket: &mut WebSocket) {
+        socket.add_method("topic.create", self.clone().set_type("create"));
+        socket.add_method("topic.subscribe", self.clone().set_type("subscribe"));
+        socket.add_method("topic.publish", self.clone().set_type("publish"));
+        socket.add_method("topic.unsubscribe", self.clone().set_type("unsubscribe"));
+        socket.add_method("topic.delete", self.clone().set_type("delete"));
+        socket.add_method("topic.list", self.clone().set_type("list"));
+        socket.add_method("topic.info", self.clone().set_type("info"));
+        socket.add_method("topic.ack", self.clone().set_type("ack"));
+        socket.add_method("topic.request", self.clone().set_type("request"));
+    }
+
+    /// Queue a command for the router. Fails if the router is gone.
+    fn transmit(&self, c: RouterCommand) -> Result<Value, ResponseError> {
+        if self.router.send(c).is_ok() {
+            return Ok(Value::Null);
         }
+        error!("[topic] Router is not accepting commands");
+        Err(ResponseError::RouterUnavailable)
+    }
+
+    /// Queue the query built by `query` for the router, then answer the
+    /// call through `reply` with what `answer` makes of the `replies`
+    /// answers to it. The wait is left to the workers, so that the event
+    /// loop is free meanwhile.
+    fn ask<T, F, A>(&self,
+                    replies: usize,
+                    query: F,
+                    reply: Responder,
+                    answer: A)
+                    -> Result<(), ResponseError>
+        where T: Send + 'static,
+              F: FnOnce(mpsc::Sender<T>) -> RouterCommand,
+              A: FnOnce(Result<Vec<T>, ResponseError>) -> Result<Value, ResponseError>,
+              A: Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        try!(self.transmit(query(tx)));
+        try!(self.workers.run(move || reply.respond(answer(wait(&rx, replies)))));
+        Ok(())
+    }
+
+    /// Fail unless `conn` has `right` on `topic_id`
+    fn check(&self, conn: &Connection, right: Right, topic_id: &str) -> Result<(), ResponseError> {
+        if self.acl.allows(conn.identity().as_ref(), right, topic_id) {
+            return Ok(());
+        }
+        debug!("[topic] Connection {} may not {} on {}",
+               conn.id,
+               right.name(),
+               topic_id);
+        Err(ResponseError::Unauthorized)
+    }
+
+    /// Key of the subscription of `conn` with the optional `name`
+    fn subscriber_id(conn: &Connection, name: Option<String>) -> String {
+        match name {
+            Some(n) => format!("{}/{}", conn.session_id(), n),
+            None => conn.session_id(),
+        }
+    }
+
+    /// Read params naming a single, non wildcard, topic
+    fn topic_key(params: Value) -> Result<String, ResponseError> {
+        let p = try!(from_value::<TopicKey>(params).map_err(|_| ResponseError::InvalidPayload));
+        if !is_topic_id(&p.topic_id) {
+            return Err(ResponseError::InvalidPayload);
+        }
+        Ok(p.topic_id)
+    }
+
+    fn subscribe(&self,
+                 conn: &Connection,
+                 params: Value,
+                 reply: Responder)
+                 -> Result<(), ResponseError> {
+        let p = try!(from_value::<TopicSubscribe>(params)
+            .map_err(|_| ResponseError::InvalidPayload));
+        if !is_valid_pattern(&p.topic_id) || is_inbox(&p.topic_id) {
+            return Err(ResponseError::InvalidPayload);
+        }
+        try!(self.check(conn, Right::Subscribe, &p.topic_id));
+        let filter = match p.filter {
+            Some(ref f) => {
+                Some(try!(Filter::parse(f).map_err(|e| {
+                    debug!("[topic] Invalid filter {:?}: {}", f, e);
+                    ResponseError::InvalidPayload
+                })))
+            }
+            None => None,
+        };
+        let opts = SubscribeOptions {
+            from_seq: p.from_seq,
+            last_n: p.last_n,
+        };
+        let subscriber = Subscriber::new(conn.id, conn.sender.clone(), conn.encoding)
+            .in_group(p.group)
+            .with_acks(p.ack.unwrap_or(false))
+            .with_filter(filter);
+        let shards = if is_pattern(&p.topic_id) { self.router.shards() } else { 1 };
+        let (topic_id, id) = (p.topic_id, TopicAPI::subscriber_id(conn, p.subscriber_id));
+        let (tid, sid, connection) = (topic_id.clone(), id.clone(), conn.id);
+        let router = self.router.clone();
+        self.ask(shards,
+                 |tx| RouterCommand::Subscribe(topic_id, id, subscriber, opts, tx),
+                 reply,
+                 move |results| {
+            let failed = match results {
+                Ok(r) => r.into_iter().filter_map(|r| r.err()).next(),
+                Err(e) => Some(e),
+            };
+            match failed {
+                // Drop what the other shards made of a pattern
+                // subscription, so that it is made everywhere or nowhere
+                Some(e) => {
+                    if shards > 1 {
+                        let _ = router.send(RouterCommand::Unsubscribe(tid, sid, connection));
+                    }
+                    Err(e)
+                }
+                None => Ok(Value::Null),
+            }
+        })
+    }
+
+    fn delete(&self,
+              conn: &Connection,
+              params: Value,
+              reply: Responder)
+              -> Result<(), ResponseError> {
+        let id = try!(TopicAPI::topic_key(params));
+        try!(self.check(conn, Right::Create, &id));
+        self.ask(1,
+                 |tx| RouterCommand::DeleteTopic(id, tx),
+                 reply,
+                 |existed| {
+            if try!(existed).into_iter().all(|e| e) {
+                Ok(Value::Null)
+            } else {
+                Err(ResponseError::NotFound)
+            }
+        })
+    }
+
+    fn list(&self,
+            conn: &Connection,
+            params: Value,
+            reply: Responder)
+            -> Result<(), ResponseError> {
+        let p = try!(from_value::<TopicList>(params).map_err(|_| ResponseError::InvalidPayload));
+        let limit = match p.limit {
+            Some(0) => return Err(ResponseError::InvalidPayload),
+            Some(n) if n < MAX_LIST_LIMIT => n,
+            Some(_) => MAX_LIST_LIMIT,
+            None => DEFAULT_LIST_LIMIT,
+        };
+        // Ask every shard for one more than a page, to tell if there is a
+        // next one
+        let prefix = p.prefix.unwrap_or_else(String::new);
+        let after = p.after;
+        // Topics the connection has no right on are left out before the
+        // page is cut, so that it still fills up
+        let (acl, identity) = (self.acl.clone(), conn.identity());
+        let visible: Visible = Arc::new(move |t| {
+            acl.allows(identity.as_ref(), Right::Subscribe, t)
+        });
+        self.ask(self.router.shards(),
+                 |tx| RouterCommand::ListTopics(prefix, after, limit + 1, visible, tx),
+                 reply,
+                 move |pages| {
+            let mut topics: Vec<String> = try!(pages).into_iter().flat_map(|p| p).collect();
+            topics.sort();
+            let next = if topics.len() > limit {
+                topics.truncate(limit);
+                topics.last().cloned()
+            } else {
+                None
+            };
+            Ok(to_value(&TopicListing {
+                topics: topics,
+                next: next,
+            }))
+        })
+    }
+
+    fn info(&self,
+            conn: &Connection,
+            params: Value,
+            reply: Responder)
+            -> Result<(), ResponseError> {
+        let id = try!(TopicAPI::topic_key(params));
+        try!(self.check(conn, Right::Subscribe, &id));
+        self.ask(1,
+                 |tx| RouterCommand::DescribeTopic(id, tx),
+                 reply,
+                 |info| {
+            match try!(info).into_iter().next() {
+                Some(Some(i)) => Ok(to_value(&i)),
+                _ => Err(ResponseError::NotFound),
+            }
+        })
+    }
+
+    /// Publish a request with a new reply-to inbox, which the router
+    /// answers the call from once the reply or the timeout comes
+    fn request(&self,
+               conn: &Connection,
+               params: Value,
+               reply: Responder)
+               -> Result<(), ResponseError> {
+        let p = try!(from_value::<TopicRequest>(params)
+            .map_err(|_| ResponseError::InvalidPayload));
+        if !is_topic_id(&p.topic_id) || is_inbox(&p.topic_id) {
+            return Err(ResponseError::InvalidPayload);
+        }
+        try!(check_duration(p.timeout_ms));
+        try!(self.check(conn, Right::Publish, &p.topic_id));
+        // Random, so that only the responders that got the request can
+        // tell where to reply and with which correlation id
+        let inbox = format!("{}{}", INBOX_PREFIX, try!(random_id()));
+        let correlation_id = try!(random_id());
+
+        // The inbox opens before the request goes out, so that no reply
+        // can get there first
+        try!(self.transmit(RouterCommand::AwaitReply(inbox.clone(),
+                                                     correlation_id.clone(),
+                                                     reply,
+                                                     p.timeout_ms)));
+        let opts = PublishOptions {
+            reply_to: Some(inbox),
+            correlation_id: Some(correlation_id),
+            sender: Some(conn.session_id()),
+            ..PublishOptions::default()
+        };
+        try!(self.transmit(RouterCommand::Send(p.topic_id,
+                                               Some(conn.publisher_id()),
+                                               WSMessage::text(p.message),
+                                               opts)));
+        Ok(())
     }
 }
 
-impl APIHandlerCommand for TopicAPI {
-    fn execute(&mut self, s: WSSender, m: WSMessage) -> Option<MessageResponse> {
-        let invalid_payload = Some(MessageResponse::error("topic", "InvalidPayload"));
+/// Check a ttl or timeout given by a client. Zero, and anything longer
+/// than `MAX_DURATION_MS`, are refused.
+fn check_duration(ms: Option<u64>) -> Result<(), ResponseError> {
+    match ms {
+        Some(0) => Err(ResponseError::InvalidPayload),
+        Some(ms) if ms > MAX_DURATION_MS => Err(ResponseError::InvalidPayload),
+        _ => Ok(()),
+    }
+}
+
+/// Wait for `replies` answers on `rx`
+fn wait<T>(rx: &mpsc::Receiver<T>, replies: usize) -> Result<Vec<T>, ResponseError> {
+    let timeout = Duration::from_millis(REPLY_TIMEOUT_MS);
+    let mut out = Vec::with_capacity(replies);
+    for _ in 0..replies {
+        match rx.recv_timeout(timeout) {
+            Ok(r) => out.push(r),
+            Err(e) => {
+                error!("[topic] No reply from router: {:?}", e);
+                return Err(ResponseError::RouterUnavailable);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A random id, as hex
+fn random_id() -> Result<String, ResponseError> {
+    let mut rng = try!(OsRng::new().map_err(|e| {
+        error!("[topic] Unable to get random id: {}", e);
+        ResponseError::InternalError
+    }));
+    let mut bytes = [0u8; REQUEST_ID_LEN];
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
 
+impl APIHandlerCommand for TopicAPI {
+    fn execute(&mut self, conn: &Connection, params: Value) -> Result<Value, ResponseError> {
         match self.actiontype {
             Some(ActionType::Create) => {
-                if let Ok(q) = from_str::<MessageRequestText<TopicCreate>>(m.as_text().unwrap_or("")) {
-                    self.transmit(RouterCommand::CreateTopic(q.payload.unwrap().topic_id));
-                    return None;
-                } else {
-                    return invalid_payload;
+                let p = try!(from_value::<TopicCreate>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                if !is_topic_id(&p.topic_id) || is_inbox(&p.topic_id) {
+                    return Err(ResponseError::InvalidPayload);
                 }
+                try!(self.check(conn, Right::Create, &p.topic_id));
+                let opts = TopicOptions {
+                    history_size: p.history_size,
+                    history_age_ms: p.history_age_ms,
+                    durable: p.durable,
+                };
+                self.transmit(RouterCommand::CreateTopic(p.topic_id, opts))
             }
             Some(ActionType::Publish) => {
-                if let Ok(q) = from_str::<MessageRequestText<TopicPublish>>(m.as_text().unwrap_or("")) {
-                    let payload = q.payload.unwrap();
-                    self.transmit(RouterCommand::Send(payload.topic_id,
-                                                      payload.publisher_id,
-                                                      WSMessage::text(payload.message)));
-                    return None;
-                } else {
-                    return invalid_payload;
+                let p = try!(from_value::<TopicPublish>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                if !is_topic_id(&p.topic_id) {
+                    return Err(ResponseError::InvalidPayload);
                 }
+                try!(check_duration(p.ttl_ms));
+                try!(self.check(conn, Right::Publish, &p.topic_id));
+                let opts = PublishOptions {
+                    retain: p.retain.unwrap_or(false),
+                    correlation_id: p.correlation_id,
+                    ttl_ms: p.ttl_ms,
+                    sender: Some(conn.session_id()),
+                    ..PublishOptions::default()
+                };
+                self.transmit(RouterCommand::Send(p.topic_id,
+                                                  Some(conn.publisher_id()),
+                                                  WSMessage::text(p.message),
+                                                  opts))
+            }
+            Some(ActionType::Unsubscribe) => {
+                let p = try!(from_value::<TopicSubscribe>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                let id = TopicAPI::subscriber_id(conn, p.subscriber_id);
+                self.transmit(RouterCommand::Unsubscribe(p.topic_id, id, conn.id))
             }
-            Some(ActionType::Subscribe) => {
-                if let Ok(q) = from_str::<MessageRequestText<TopicSubscribe>>(m.as_text().unwrap_or("")) {
-                    let payload = q.payload.unwrap();
-                    self.transmit(RouterCommand::Subscribe(payload.topic_id,
-                                                           payload.subscriber_id,
-                                                           s.clone()));
-                    return None;
-                } else {
-                    return invalid_payload;
+            Some(ActionType::Ack) => {
+                let p = try!(from_value::<TopicAck>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                if !is_topic_id(&p.topic_id) {
+                    return Err(ResponseError::InvalidPayload);
                 }
+                try!(self.check(conn, Right::Subscribe, &p.topic_id));
+                let id = TopicAPI::subscriber_id(conn, p.subscriber_id);
+                self.transmit(RouterCommand::Ack(p.topic_id, id, p.delivery_id))
             }
-            Some(ActionType::Unsubscribe) => {
-                if let Ok(q) = from_str::<MessageRequestText<TopicSubscribe>>(m.as_text().unwrap_or("")) {
-                    let payload = q.payload.unwrap();
-                    self.transmit(RouterCommand::Unsubscribe(payload.topic_id,
-                                                             payload.subscriber_id));
-                    return None;
-                } else {
-                    return invalid_payload;
+            // Answered through `execute_deferred`
+            Some(_) => Err(ResponseError::InvalidRequest),
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+
+    fn is_deferred(&self) -> bool {
+        match self.actiontype {
+            Some(ActionType::Subscribe) |
+            Some(ActionType::Delete) |
+            Some(ActionType::List) |
+            Some(ActionType::Info) |
+            Some(ActionType::Request) => true,
+            _ => false,
+        }
+    }
+
+    fn execute_deferred(&mut self,
+                        conn: &Connection,
+                        params: Value,
+                        reply: Responder)
+                        -> Result<(), ResponseError> {
+        match self.actiontype {
+            Some(ActionType::Subscribe) => self.subscribe(conn, params, reply),
+            Some(ActionType::Delete) => self.delete(conn, params, reply),
+            Some(ActionType::List) => self.list(conn, params, reply),
+            Some(ActionType::Info) => self.info(conn, params, reply),
+            Some(ActionType::Request) => self.request(conn, params, reply),
+            Some(_) => Err(ResponseError::InvalidRequest),
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+
+    fn execute_binary(&mut self,
+                      conn: &Connection,
+                      params: Value,
+                      payload: Vec<u8>)
+                      -> Result<Value, ResponseError> {
+        match self.actiontype {
+            Some(ActionType::Publish) => {
+                let p = try!(from_value::<TopicPublishBinary>(params)
+                    .map_err(|_| ResponseError::InvalidPayload));
+                if !is_topic_id(&p.topic_id) {
+                    return Err(ResponseError::InvalidPayload);
                 }
+                try!(check_duration(p.ttl_ms));
+                try!(self.check(conn, Right::Publish, &p.topic_id));
+                let opts = PublishOptions {
+                    retain: p.retain.unwrap_or(false),
+                    ttl_ms: p.ttl_ms,
+                    sender: Some(conn.session_id()),
+                    ..PublishOptions::default()
+                };
+                self.transmit(RouterCommand::Send(p.topic_id,
+                                                  Some(conn.publisher_id()),
+                                                  WSMessage::Binary(payload),
+                                                  opts))
             }
-            None => None,
+            Some(_) => Err(ResponseError::InvalidRequest),
+            None => Err(ResponseError::MethodNotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+    use ws;
+
+    use network::websocket::Identity;
+    use schema::config_schema::{AclConfig, AclRule, RouterConfig};
+    use testing::{RECV_TIMEOUT_MS, connect};
+
+    fn connection() -> Connection {
+        Connection::new(1, ws::WebSocket::new(|_| |_| Ok(())).unwrap().broadcaster())
+    }
+
+    fn api(action: &str) -> TopicAPI {
+        TopicAPI::with_router(Router::spawn(RouterConfig::default(), None), Workers::spawn(1))
+            .set_type(action)
+    }
+
+    fn params(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Access control letting `account:alice` do anything on `alice/#`
+    fn alice_only() -> Acl {
+        let rule = AclRule {
+            topic: "alice/#".to_string(),
+            identity: Some("account:alice".to_string()),
+            role: None,
+            allow: vec!["publish".to_string(), "subscribe".to_string(), "create".to_string()],
+        };
+        let conf = AclConfig {
+            enabled: true,
+            admins: Vec::new(),
+            rules: vec![rule],
+        };
+        Acl::new(&conf).unwrap()
+    }
+
+    #[test]
+    fn refuses_calls_the_acl_does_not_allow() {
+        let conn = connection();
+        conn.authenticate(Identity::account("alice".to_string()));
+        for action in &["create", "publish"] {
+            let mut api = api(action).with_acl(alice_only());
+            let p = params(r#"{"topic_id": "t", "message": "m"}"#);
+            assert_eq!(api.execute(&conn, p).err(), Some(ResponseError::Unauthorized));
+            let p = params(r#"{"topic_id": "alice/t", "message": "m"}"#);
+            assert!(api.execute(&conn, p).is_ok());
+        }
+        let mut api = api("subscribe").with_acl(alice_only());
+        for topic_id in &["t", "#", "+/t"] {
+            let p = params(&format!(r#"{{"topic_id": "{}"}}"#, topic_id));
+            let reply = Responder::new(&conn, None);
+            assert_eq!(api.execute_deferred(&conn, p, reply).err(),
+                       Some(ResponseError::Unauthorized));
+        }
+    }
+
+    #[test]
+    fn lists_pages_of_the_topics_the_connection_may_subscribe_to() {
+        let (sender, frames) = connect();
+        let conn = Connection::new(1, sender);
+        conn.authenticate(Identity::account("alice".to_string()));
+        let router = Router::spawn(RouterConfig::default(), None);
+        for id in &["a", "alice/1", "b", "alice/2", "c"] {
+            router.send(RouterCommand::CreateTopic(id.to_string(), TopicOptions::default()))
+                .unwrap();
+        }
+        let mut api = TopicAPI::with_router(router, Workers::spawn(1))
+            .with_acl(alice_only())
+            .set_type("list");
+
+        let mut list = |p: &str| {
+            let reply = Responder::new(&conn, Some(Value::String("list".to_string())));
+            api.execute_deferred(&conn, params(p), reply).unwrap();
+            let m = frames.recv_timeout(Duration::from_millis(RECV_TIMEOUT_MS)).unwrap();
+            let r: Value = serde_json::from_str(m.as_text().unwrap()).unwrap();
+            r.as_object().and_then(|o| o.get("result")).cloned().unwrap()
+        };
+        assert_eq!(list(r#"{"limit": 1}"#),
+                   params(r#"{"topics": ["alice/1"], "next": "alice/1"}"#));
+        assert_eq!(list(r#"{"limit": 1, "after": "alice/1"}"#),
+                   params(r#"{"topics": ["alice/2"], "next": null}"#));
+    }
+
+    #[test]
+    fn refuses_topic_ids_with_wildcard_characters() {
+        let conn = connection();
+        for id in &["a/b+", "a/#b", "a/+"] {
+            for action in &["create", "publish", "ack"] {
+                let p = params(&format!(r#"{{"topic_id": "{}", "message": "m",
+                                             "delivery_id": 1}}"#,
+                                        id));
+                assert_eq!(api(action).execute(&conn, p).err(),
+                           Some(ResponseError::InvalidPayload));
+            }
+            let p = params(&format!(r#"{{"topic_id": "{}"}}"#, id));
+            assert_eq!(api("publish").execute_binary(&conn, p, vec![1]).err(),
+                       Some(ResponseError::InvalidPayload));
+            for action in &["request", "info", "delete"] {
+                let p = params(&format!(r#"{{"topic_id": "{}", "message": "m"}}"#, id));
+                let reply = Responder::new(&conn, None);
+                assert_eq!(api(action).execute_deferred(&conn, p, reply).err(),
+                           Some(ResponseError::InvalidPayload));
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_nonsense_ttls() {
+        let conn = connection();
+        let mut api = api("publish");
+        for ttl in &["0", "31536000001", "18446744073709551615"] {
+            let p = params(&format!(r#"{{"topic_id": "t", "message": "m", "ttl_ms": {}}}"#, ttl));
+            assert_eq!(api.execute(&conn, p).err(), Some(ResponseError::InvalidPayload));
+            let p = params(&format!(r#"{{"topic_id": "t", "ttl_ms": {}}}"#, ttl));
+            assert_eq!(api.execute_binary(&conn, p, vec![1]).err(),
+                       Some(ResponseError::InvalidPayload));
+        }
+        let p = params(r#"{"topic_id": "t", "message": "m", "ttl_ms": 31536000000}"#);
+        assert!(api.execute(&conn, p).is_ok());
+    }
+
+    #[test]
+    fn refuses_nonsense_request_timeouts() {
+        let conn = connection();
+        let mut api = api("request");
+        for timeout in &["0", "18446744073709551615"] {
+            let p = params(&format!(r#"{{"topic_id": "t", "message": "m", "timeout_ms": {}}}"#,
+                                    timeout));
+            let reply = Responder::new(&conn, None);
+            assert_eq!(api.execute_deferred(&conn, p, reply).err(),
+                       Some(ResponseError::InvalidPayload));
         }
     }
 }
@@ -0,0 +1,583 @@
+//! Durable `DataStore` backed by an append-only segmented log
+//!
+//! Every `put` and `delete` is appended to the active segment file in
+//! the store directory, and synced to disk before it returns. Segments
+//! are named by an increasing id (`00000000000000000001.log`, ...) and a
+//! new one is started once the active segment grows past the configured
+//! size. All live records are also kept in memory, so reads never touch
+//! the disk.
+//!
+//! Each record on disk is laid out as (integers little endian):
+//!
+//! ```text
+//! crc32: u32 | kind: u8 | expires: u64 | key_len: u32 | value_len: u32 | key | value
+//! ```
+//!
+//! The CRC covers everything after itself. `expires` is a unix
+//! timestamp in milliseconds, `0` meaning the record never expires.
+//!
+//! On startup all segments are replayed in order. Replay of a segment
+//! stops at the first truncated or corrupt record, and the segment is
+//! truncated there, which recovers from a crash in the middle of a
+//! write. Compaction rewrites the live records into fresh segments and
+//! removes the old ones.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{DataStore, poisoned};
+use util::now_millis;
+
+const SEGMENT_EXT: &'static str = "log";
+const COMPACT_EXT: &'static str = "compact";
+const HEADER_LEN: usize = 21;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+/// Options for a `DiskStore`
+#[derive(Clone, Debug)]
+pub struct DiskOptions {
+    /// Size in bytes after which a new segment is started
+    pub segment_size: u64,
+}
+
+impl Default for DiskOptions {
+    fn default() -> Self {
+        DiskOptions { segment_size: 16 * 1024 * 1024 }
+    }
+}
+
+struct Record {
+    value: Vec<u8>,
+    expires: u64,
+}
+
+impl Record {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+struct Inner {
+    dir: PathBuf,
+    opts: DiskOptions,
+    records: BTreeMap<String, Record>,
+    segments: Vec<u64>,
+    active: File,
+    active_size: u64,
+    /// Bytes on disk taken by overwritten, deleted or expired records
+    garbage: u64,
+}
+
+/// `DataStore` persisted to an append-only log on disk
+pub struct DiskStore {
+    inner: Mutex<Inner>,
+}
+
+fn segment_path(dir: &Path, id: u64, ext: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, ext))
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    for i in 0..4 {
+        buf.push((v >> (8 * i)) as u8);
+    }
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    for i in 0..8 {
+        buf.push((v >> (8 * i)) as u8);
+    }
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    b.iter().take(4).enumerate().fold(0, |v, (i, x)| v | (*x as u32) << (8 * i))
+}
+
+fn get_u64(b: &[u8]) -> u64 {
+    b.iter().take(8).enumerate().fold(0, |v, (i, x)| v | (*x as u64) << (8 * i))
+}
+
+/// CRC-32 (IEEE 802.3) checksum
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn encode(kind: u8, key: &str, value: &[u8], expires: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    put_u32(&mut buf, 0);
+    buf.push(kind);
+    put_u64(&mut buf, expires);
+    put_u32(&mut buf, key.len() as u32);
+    put_u32(&mut buf, value.len() as u32);
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(value);
+    let crc = crc32(&buf[4..]);
+    for i in 0..4 {
+        buf[i] = (crc >> (8 * i)) as u8;
+    }
+    buf
+}
+
+/// A record read back from a segment
+struct Entry {
+    kind: u8,
+    expires: u64,
+    key: String,
+    value: Vec<u8>,
+    len: u64,
+}
+
+/// Decode the record at the start of `buf`. Returns `None` if the
+/// record is truncated or fails its checksum.
+fn decode(buf: &[u8]) -> Option<Entry> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    let klen = get_u32(&buf[13..]) as usize;
+    let vlen = get_u32(&buf[17..]) as usize;
+    let len = HEADER_LEN + klen + vlen;
+    if buf.len() < len || crc32(&buf[4..len]) != get_u32(buf) {
+        return None;
+    }
+    let key = match String::from_utf8(buf[HEADER_LEN..HEADER_LEN + klen].to_vec()) {
+        Ok(k) => k,
+        Err(_) => return None,
+    };
+    Some(Entry {
+        kind: buf[4],
+        expires: get_u64(&buf[5..]),
+        key: key,
+        value: buf[HEADER_LEN + klen..len].to_vec(),
+        len: len as u64,
+    })
+}
+
+impl DiskStore {
+    /// Open the store in `dir`, creating the directory if needed, and
+    /// recover its contents from the segments found there.
+    pub fn open<P: AsRef<Path>>(dir: P, opts: DiskOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+
+        let mut segments = Vec::new();
+        for entry in try!(fs::read_dir(&dir)) {
+            let path = try!(entry).path();
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            if ext == COMPACT_EXT {
+                // Left over from an interrupted compaction
+                try!(fs::remove_file(&path));
+                continue;
+            }
+            if ext != SEGMENT_EXT {
+                continue;
+            }
+            let id = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok());
+            if let Some(id) = id {
+                segments.push(id);
+            }
+        }
+        segments.sort();
+
+        let mut records = BTreeMap::new();
+        let mut garbage = 0;
+        let mut active_size = 0;
+        for id in &segments {
+            active_size = try!(DiskStore::replay(&segment_path(&dir, *id, SEGMENT_EXT),
+                                                 &mut records,
+                                                 &mut garbage));
+        }
+
+        if segments.is_empty() {
+            segments.push(1);
+        }
+        let active_id = segments[segments.len() - 1];
+        let active = try!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, active_id, SEGMENT_EXT)));
+
+        info!("[datastore] Opened {:?}: {} record(s) in {} segment(s)",
+              dir,
+              records.len(),
+              segments.len());
+
+        Ok(DiskStore {
+            inner: Mutex::new(Inner {
+                dir: dir,
+                opts: opts,
+                records: records,
+                segments: segments,
+                active: active,
+                active_size: active_size,
+                garbage: garbage,
+            }),
+        })
+    }
+
+    /// Apply all records of the segment at `path`, truncating it at the
+    /// first bad record. Returns the size of the valid part.
+    fn replay(path: &Path,
+              records: &mut BTreeMap<String, Record>,
+              garbage: &mut u64)
+              -> Result<u64> {
+        let mut buf = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut buf));
+
+        let mut offset = 0;
+        while (offset as usize) < buf.len() {
+            let e = match decode(&buf[offset as usize..]) {
+                Some(e) => e,
+                None => break,
+            };
+            offset += e.len;
+            if let Some(old) = records.remove(&e.key) {
+                *garbage += (HEADER_LEN + e.key.len() + old.value.len()) as u64;
+            }
+            if e.kind == KIND_PUT {
+                records.insert(e.key,
+                               Record {
+                                   value: e.value,
+                                   expires: e.expires,
+                               });
+            } else {
+                *garbage += e.len;
+            }
+        }
+
+        if (offset as usize) < buf.len() {
+            warn!("[datastore] Truncating {:?} at byte {}: {} trailing byte(s) are corrupt",
+                  path,
+                  offset,
+                  buf.len() as u64 - offset);
+            let f = try!(OpenOptions::new().write(true).open(path));
+            try!(f.set_len(offset));
+            try!(f.sync_all());
+        }
+        Ok(offset)
+    }
+
+    /// Rewrite all live records into fresh segments and remove the old
+    /// ones.
+    pub fn compact(&self) -> Result<()> {
+        let mut inner = try!(self.inner.lock().map_err(poisoned));
+        inner.compact()
+    }
+}
+
+impl Inner {
+    /// Bytes on disk taken by live records
+    fn live_size(&self) -> u64 {
+        self.records.iter().fold(0, |s, (k, r)| s + (HEADER_LEN + k.len() + r.value.len()) as u64)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        if self.active_size > 0 && self.active_size + buf.len() as u64 > self.opts.segment_size {
+            try!(self.roll());
+        }
+        let written = self.active.write_all(buf).and_then(|_| self.active.sync_data());
+        if let Err(e) = written {
+            // Cut off whatever part of the record was written, so that the
+            // next one follows the last whole record
+            if let Err(t) = self.active.set_len(self.active_size) {
+                error!("[datastore] Unable to truncate {:?} after a failed write: {}",
+                       self.dir,
+                       t);
+            }
+            return Err(e);
+        }
+        self.active_size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Start a new active segment
+    fn roll(&mut self) -> Result<()> {
+        try!(self.active.sync_all());
+        let id = self.segments[self.segments.len() - 1] + 1;
+        self.active = try!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, id, SEGMENT_EXT)));
+        self.segments.push(id);
+        self.active_size = 0;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let now = now_millis();
+        let expired: Vec<String> = self.records
+            .iter()
+            .filter(|&(_, r)| r.is_expired(now))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in expired {
+            self.records.remove(&k);
+        }
+
+        // Write the live records into new segments numbered after the
+        // current ones, so that if we crash before the old segments are
+        // removed, replay still ends with the compacted state.
+        let first = self.segments[self.segments.len() - 1] + 1;
+        let mut ids = vec![first];
+        let mut out = try!(File::create(segment_path(&self.dir, first, COMPACT_EXT)));
+        let mut size = 0;
+        for (k, r) in &self.records {
+            let buf = encode(KIND_PUT, k, &r.value, r.expires);
+            if size > 0 && size + buf.len() as u64 > self.opts.segment_size {
+                try!(out.sync_all());
+                let id = ids[ids.len() - 1] + 1;
+                out = try!(File::create(segment_path(&self.dir, id, COMPACT_EXT)));
+                ids.push(id);
+                size = 0;
+            }
+            try!(out.write_all(&buf));
+            size += buf.len() as u64;
+        }
+        try!(out.sync_all());
+
+        for id in &ids {
+            try!(fs::rename(segment_path(&self.dir, *id, COMPACT_EXT),
+                            segment_path(&self.dir, *id, SEGMENT_EXT)));
+        }
+        for id in &self.segments {
+            try!(fs::remove_file(segment_path(&self.dir, *id, SEGMENT_EXT)));
+        }
+
+        let active_id = ids[ids.len() - 1];
+        self.active = try!(OpenOptions::new()
+            .append(true)
+            .open(segment_path(&self.dir, active_id, SEGMENT_EXT)));
+        debug!("[datastore] Compacted {:?}: {} segment(s) replaced by {}",
+               self.dir,
+               self.segments.len(),
+               ids.len());
+        self.segments = ids;
+        self.active_size = size;
+        self.garbage = 0;
+        Ok(())
+    }
+}
+
+impl DataStore for DiskStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let inner = try!(self.inner.lock().map_err(poisoned));
+        let now = now_millis();
+        Ok(inner.records
+            .get(key)
+            .and_then(|r| if r.is_expired(now) { None } else { Some(r.value.clone()) }))
+    }
+
+    fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        let mut inner = try!(self.inner.lock().map_err(poisoned));
+        let expires = ttl.map_or(0, |t| {
+            let ms = t.as_secs().saturating_mul(1000);
+            now_millis().saturating_add(ms).saturating_add((t.subsec_nanos() / 1_000_000) as u64)
+        });
+        try!(inner.append(&encode(KIND_PUT, key, &value, expires)));
+        let old = inner.records.insert(key.to_string(),
+                                       Record {
+                                           value: value,
+                                           expires: expires,
+                                       });
+        if let Some(old) = old {
+            inner.garbage += (HEADER_LEN + key.len() + old.value.len()) as u64;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        let mut inner = try!(self.inner.lock().map_err(poisoned));
+        if !inner.records.contains_key(key) {
+            return Ok(false);
+        }
+        // The tombstone goes to disk first, so that the record is not
+        // gone from memory while it would come back on restart
+        let buf = encode(KIND_DELETE, key, &[], 0);
+        try!(inner.append(&buf));
+        let old = match inner.records.remove(key) {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        inner.garbage += (HEADER_LEN + key.len() + old.value.len() + buf.len()) as u64;
+        Ok(!old.is_expired(now_millis()))
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let inner = try!(self.inner.lock().map_err(poisoned));
+        let now = now_millis();
+        Ok(inner.records
+            .range(prefix.to_string()..)
+            .take_while(|&(k, _)| k.starts_with(prefix))
+            .filter(|&(_, r)| !r.is_expired(now))
+            .map(|(k, r)| (k.clone(), r.value.clone()))
+            .collect())
+    }
+
+    /// Compact the log once more than half of it is garbage
+    fn maintain(&self) -> Result<()> {
+        let mut inner = try!(self.inner.lock().map_err(poisoned));
+        if inner.garbage > inner.live_size() {
+            try!(inner.compact());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom};
+
+    use testing::scratch;
+
+    fn segment_len(dir: &PathBuf, id: u64) -> u64 {
+        fs::metadata(segment_path(dir, id, SEGMENT_EXT)).unwrap().len()
+    }
+
+    fn segment_count(dir: &PathBuf) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn crc32_matches_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn reopens_with_puts_and_deletes() {
+        let dir = scratch("disk-reopen");
+        {
+            let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+            store.put("a", b"1".to_vec(), None).unwrap();
+            store.put("b", b"2".to_vec(), None).unwrap();
+            store.put("a", b"3".to_vec(), None).unwrap();
+            assert!(store.delete("b").unwrap());
+            assert!(!store.delete("b").unwrap());
+        }
+        let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get("b").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_records_with_the_longest_ttl() {
+        let dir = scratch("disk-ttl");
+        {
+            let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+            let ttl = Duration::new(u64::max_value(), 999_999_999);
+            store.put("a", b"1".to_vec(), Some(ttl)).unwrap();
+            assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+        }
+        let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_records_failing_crc() {
+        let dir = scratch("disk-crc");
+        let first;
+        {
+            let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+            store.put("a", b"good".to_vec(), None).unwrap();
+            first = segment_len(&dir, 1);
+            store.put("b", b"evil".to_vec(), None).unwrap();
+        }
+        // Flip a bit in the last byte, the value of the second record
+        {
+            let path = segment_path(&dir, 1, SEGMENT_EXT);
+            let mut f = OpenOptions::new().read(true).write(true).open(path).unwrap();
+            let mut buf = Vec::new();
+            f.read_to_end(&mut buf).unwrap();
+            let last = buf.len() - 1;
+            f.seek(SeekFrom::Start(last as u64)).unwrap();
+            f.write_all(&[buf[last] ^ 1]).unwrap();
+        }
+        let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"good".to_vec()));
+        assert_eq!(store.get("b").unwrap(), None);
+        assert_eq!(segment_len(&dir, 1), first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_truncated_tail() {
+        let dir = scratch("disk-tail");
+        let first;
+        {
+            let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+            store.put("a", b"1".to_vec(), None).unwrap();
+            first = segment_len(&dir, 1);
+            store.put("b", b"2".to_vec(), None).unwrap();
+        }
+        // Cut the second record short, as a crash in the middle of the
+        // write would
+        {
+            let path = segment_path(&dir, 1, SEGMENT_EXT);
+            let f = OpenOptions::new().write(true).open(path).unwrap();
+            f.set_len(first + 3).unwrap();
+        }
+        {
+            let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+            assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(store.get("b").unwrap(), None);
+            assert_eq!(segment_len(&dir, 1), first);
+            store.put("c", b"3".to_vec(), None).unwrap();
+        }
+        // Records written after recovery follow on from the good ones
+        let store = DiskStore::open(&dir, DiskOptions::default()).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get("c").unwrap(), Some(b"3".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_only_live_records() {
+        let dir = scratch("disk-compact");
+        let opts = DiskOptions { segment_size: 64 };
+        {
+            let store = DiskStore::open(&dir, opts.clone()).unwrap();
+            for i in 0..20 {
+                store.put(&format!("k{:02}", i), vec![i as u8; 8], None).unwrap();
+            }
+            for i in 0..20 {
+                store.put(&format!("k{:02}", i), vec![i as u8 + 1; 8], None).unwrap();
+            }
+            for i in 0..10 {
+                store.delete(&format!("k{:02}", i)).unwrap();
+            }
+            store.put("gone", b"x".to_vec(), Some(Duration::from_millis(1))).unwrap();
+            let before = segment_count(&dir);
+            ::std::thread::sleep(Duration::from_millis(5));
+
+            store.maintain().unwrap();
+            assert!(segment_count(&dir) < before);
+            assert_eq!(store.scan("k").unwrap().len(), 10);
+            assert_eq!(store.get("gone").unwrap(), None);
+        }
+        let store = DiskStore::open(&dir, opts).unwrap();
+        for i in 0..10 {
+            assert_eq!(store.get(&format!("k{:02}", i)).unwrap(), None);
+        }
+        for i in 10..20 {
+            assert_eq!(store.get(&format!("k{:02}", i)).unwrap(), Some(vec![i as u8 + 1; 8]));
+        }
+        assert_eq!(store.get("gone").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! In-memory `DataStore`
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use super::{DataStore, poisoned};
+
+/// Longest ttl, a century. Longer ones are cut down to it, so that the
+/// expiry always fits in an `Instant`.
+const MAX_TTL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+struct Record {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+}
+
+impl Record {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.map_or(false, |e| e <= now)
+    }
+}
+
+/// Thread-safe `DataStore` that keeps all records in memory
+#[derive(Default)]
+pub struct MemoryStore {
+    records: RwLock<BTreeMap<String, Record>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore { records: RwLock::new(BTreeMap::new()) }
+    }
+
+    /// Drop all expired records. Expired records are never returned,
+    /// this only frees their memory.
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        if let Ok(mut r) = self.records.write() {
+            let expired: Vec<String> = r.iter()
+                .filter(|&(_, v)| v.is_expired(now))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in expired {
+                r.remove(&k);
+            }
+        }
+    }
+}
+
+impl DataStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let r = try!(self.records.read().map_err(poisoned));
+        Ok(r.get(key)
+            .and_then(|v| if v.is_expired(Instant::now()) { None } else { Some(v.value.clone()) }))
+    }
+
+    fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        let mut r = try!(self.records.write().map_err(poisoned));
+        r.insert(key.to_string(),
+                 Record {
+                     value: value,
+                     expires: ttl.map(|t| {
+                         Instant::now() + cmp::min(t, Duration::from_secs(MAX_TTL_SECS))
+                     }),
+                 });
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        let mut r = try!(self.records.write().map_err(poisoned));
+        let now = Instant::now();
+        Ok(r.remove(key).map_or(false, |v| !v.is_expired(now)))
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let r = try!(self.records.read().map_err(poisoned));
+        let now = Instant::now();
+        Ok(r.range(prefix.to_string()..)
+            .take_while(|&(k, _)| k.starts_with(prefix))
+            .filter(|&(_, v)| !v.is_expired(now))
+            .map(|(k, v)| (k.clone(), v.value.clone()))
+            .collect())
+    }
+
+    fn maintain(&self) -> Result<()> {
+        self.purge_expired();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn keys(records: Vec<(String, Vec<u8>)>) -> Vec<String> {
+        records.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn gets_puts_and_deletes() {
+        let store = MemoryStore::new();
+        assert_eq!(store.get("a").unwrap(), None);
+        store.put("a", b"one".to_vec(), None).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"one".to_vec()));
+        store.put("a", b"two".to_vec(), None).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"two".to_vec()));
+        assert!(store.delete("a").unwrap());
+        assert!(!store.delete("a").unwrap());
+        assert_eq!(store.get("a").unwrap(), None);
+    }
+
+    #[test]
+    fn expires_records() {
+        let store = MemoryStore::new();
+        store.put("short", b"1".to_vec(), Some(Duration::from_millis(20))).unwrap();
+        store.put("long", b"2".to_vec(), Some(Duration::from_secs(60))).unwrap();
+        store.put("forever", b"3".to_vec(), Some(Duration::from_secs(u64::max_value()))).unwrap();
+        assert_eq!(store.get("short").unwrap(), Some(b"1".to_vec()));
+
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(store.get("short").unwrap(), None);
+        assert!(!store.delete("short").unwrap());
+        assert_eq!(keys(store.scan("").unwrap()), vec!["forever", "long"]);
+
+        store.put("short", b"1".to_vec(), Some(Duration::from_millis(1))).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        store.maintain().unwrap();
+        assert_eq!(store.records.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn scans_by_prefix_in_key_order() {
+        let store = MemoryStore::new();
+        for k in &["b/2", "a", "b/10", "b", "ba", "c/1", "b/1"] {
+            store.put(k, k.as_bytes().to_vec(), None).unwrap();
+        }
+        assert_eq!(keys(store.scan("b/").unwrap()), vec!["b/1", "b/10", "b/2"]);
+        assert_eq!(keys(store.scan("b").unwrap()), vec!["b", "b/1", "b/10", "b/2", "ba"]);
+        assert_eq!(store.scan("b/1").unwrap(),
+                   vec![("b/1".to_string(), b"b/1".to_vec()),
+                        ("b/10".to_string(), b"b/10".to_vec())]);
+        assert!(store.scan("d").unwrap().is_empty());
+        assert_eq!(store.scan("").unwrap().len(), 7);
+    }
+}
@@ -1 +1,37 @@
 //! `DataStore` abstraction for `unicorn`.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+mod disk;
+mod memory;
+
+pub use self::disk::{DiskOptions, DiskStore};
+pub use self::memory::MemoryStore;
+
+/// A key/value store. Implementations must be safe to share between
+/// threads.
+pub trait DataStore: Send + Sync {
+    /// Get the value stored under `key`, if any
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, replacing any previous value. If
+    /// `ttl` is set, the record expires after that duration.
+    fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<()>;
+
+    /// Remove `key`. Returns `true` if a value was removed.
+    fn delete(&self, key: &str) -> Result<bool>;
+
+    /// All records whose key starts with `prefix`, ordered by key
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>>;
+
+    /// Periodic housekeeping, such as dropping expired records
+    fn maintain(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Error for a lock some other thread panicked while holding
+fn poisoned<T>(_: T) -> Error {
+    Error::new(ErrorKind::Other, "datastore lock poisoned")
+}
@@ -0,0 +1,544 @@
+//! Content-based subscription filters.
+//!
+//! A filter is an expression evaluated against the JSON body of a
+//! message, such as `temperature > 30 && site == "north"`:
+//!
+//! - fields are named by their key, with `.` reaching into nested
+//!   objects (`reading.temperature`); missing fields are `null`
+//! - literals are numbers, double quoted strings, `true`, `false` and
+//!   `null`
+//! - comparisons are `==`, `!=`, `<`, `<=`, `>` and `>=`; ordering only
+//!   holds between two numbers or two strings
+//! - `&&`, `||`, `!` and parentheses combine them, `&&` binding tighter
+//!   than `||`
+//! - a field on its own is true unless it is `false`, `null`, `0` or
+//!   `""`
+//!
+//! Messages whose body is not JSON never match.
+
+use serde_json::Value;
+
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+
+/// Deepest nesting of parentheses and `!` accepted
+const MAX_DEPTH: usize = 32;
+
+/// Most tokens an expression can be made of
+const MAX_TOKENS: usize = 256;
+
+/// Error in a filter expression, at a byte offset
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.reason, self.position)
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        self.reason
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Field(Vec<String>),
+    Literal(Literal),
+    Compare(Compare),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Field(Vec<String>),
+    Literal(Literal),
+}
+
+/// A parsed expression. Chains of `||` and `&&` are kept flat, so that
+/// only parentheses and `!` nest, at most `MAX_DEPTH` deep.
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Compare, Operand),
+    Truthy(Operand),
+}
+
+/// A parsed filter expression
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let tokens = try!(tokenize(source));
+        let mut p = Parser {
+            tokens: tokens,
+            at: 0,
+            end: source.len(),
+            depth: 0,
+        };
+        let expr = try!(p.or());
+        if p.at < p.tokens.len() {
+            return Err(p.error("unexpected token"));
+        }
+        Ok(Filter { expr: expr })
+    }
+
+    /// Check if a message body matches the filter
+    pub fn matches(&self, body: &Value) -> bool {
+        eval(&self.expr, body)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (pos, c) = chars[i];
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let err = |reason| ParseError { position: pos, reason: reason };
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Compare(Compare::Eq), 2),
+            ('!', Some('=')) => (Token::Compare(Compare::Ne), 2),
+            ('<', Some('=')) => (Token::Compare(Compare::Le), 2),
+            ('>', Some('=')) => (Token::Compare(Compare::Ge), 2),
+            ('<', _) => (Token::Compare(Compare::Lt), 1),
+            ('>', _) => (Token::Compare(Compare::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) => {
+                let mut s = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j).map(|&(_, c)| c) {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.get(j + 1).map(|&(_, c)| c) {
+                                Some(e @ '"') | Some(e @ '\\') => s.push(e),
+                                _ => return Err(err("invalid escape in string")),
+                            }
+                            j += 2;
+                        }
+                        Some(c) => {
+                            s.push(c);
+                            j += 1;
+                        }
+                        None => return Err(err("unterminated string")),
+                    }
+                }
+                (Token::Literal(Literal::Str(s)), j + 1 - i)
+            }
+            (c, _) if c.is_digit(10) || c == '-' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|&&(_, c)| {
+                        c.is_digit(10) || c == '.' || c == '-' || c == 'e' || c == 'E' || c == '+'
+                    })
+                    .count();
+                let end = chars.get(i + len).map_or(source.len(), |&(p, _)| p);
+                match source[pos..end].parse::<f64>() {
+                    Ok(n) => (Token::Literal(Literal::Number(n)), len),
+                    Err(_) => return Err(err("invalid number")),
+                }
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|&&(_, c)| c.is_alphanumeric() || c == '_' || c == '.')
+                    .count();
+                let end = chars.get(i + len).map_or(source.len(), |&(p, _)| p);
+                let word = &source[pos..end];
+                let token = match word {
+                    "true" => Token::Literal(Literal::Bool(true)),
+                    "false" => Token::Literal(Literal::Bool(false)),
+                    "null" => Token::Literal(Literal::Null),
+                    _ => {
+                        let path: Vec<String> = word.split('.').map(String::from).collect();
+                        if path.iter().any(|k| k.is_empty()) {
+                            return Err(err("invalid field name"));
+                        }
+                        Token::Field(path)
+                    }
+                };
+                (token, len)
+            }
+            _ => return Err(err("unexpected character")),
+        };
+        if out.len() == MAX_TOKENS {
+            return Err(err("expression too long"));
+        }
+        out.push((pos, token));
+        i += len;
+    }
+    Ok(out)
+}
+
+/// Recursive descent parser over the tokens of an expression
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    at: usize,
+    /// Length of the source, where errors past the last token are
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            position: self.tokens.get(self.at).map_or(self.end, |&(p, _)| p),
+            reason: reason,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at).map(|&(_, ref t)| t)
+    }
+
+    fn eat(&mut self, t: &Token) -> bool {
+        if self.peek() == Some(t) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut es = vec![try!(self.and())];
+        while self.eat(&Token::Or) {
+            es.push(try!(self.and()));
+        }
+        Ok(if es.len() == 1 { es.remove(0) } else { Expr::Or(es) })
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut es = vec![try!(self.unary())];
+        while self.eat(&Token::And) {
+            es.push(try!(self.unary()));
+        }
+        Ok(if es.len() == 1 { es.remove(0) } else { Expr::And(es) })
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("expression nested too deeply"));
+        }
+        self.depth += 1;
+        let e = if self.eat(&Token::Not) {
+            self.unary().map(|e| Expr::Not(Box::new(e)))
+        } else if self.eat(&Token::Open) {
+            let e = try!(self.or());
+            if self.eat(&Token::Close) {
+                Ok(e)
+            } else {
+                Err(self.error("expected `)`"))
+            }
+        } else {
+            self.comparison()
+        };
+        self.depth -= 1;
+        e
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let left = try!(self.operand());
+        let op = match self.peek() {
+            Some(&Token::Compare(op)) => op,
+            _ => return Ok(Expr::Truthy(left)),
+        };
+        self.at += 1;
+        let right = try!(self.operand());
+        Ok(Expr::Compare(left, op, right))
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseError> {
+        let o = match self.peek() {
+            Some(&Token::Field(ref path)) => Operand::Field(path.clone()),
+            Some(&Token::Literal(ref l)) => Operand::Literal(l.clone()),
+            Some(_) => return Err(self.error("expected a field or a value")),
+            None => return Err(self.error("unexpected end of expression")),
+        };
+        self.at += 1;
+        Ok(o)
+    }
+}
+
+/// A value of the body, as far as filters can tell values apart
+#[derive(PartialEq)]
+enum Scalar<'a> {
+    Number(f64),
+    Str(&'a str),
+    Bool(bool),
+    Null,
+    /// Objects and arrays
+    Other,
+}
+
+fn resolve<'a>(o: &'a Operand, body: &'a Value) -> Scalar<'a> {
+    match *o {
+        Operand::Literal(Literal::Number(n)) => Scalar::Number(n),
+        Operand::Literal(Literal::Str(ref s)) => Scalar::Str(s),
+        Operand::Literal(Literal::Bool(b)) => Scalar::Bool(b),
+        Operand::Literal(Literal::Null) => Scalar::Null,
+        Operand::Field(ref path) => {
+            let v = path.iter()
+                .fold(Some(body), |v, k| v.and_then(|v| v.as_object()).and_then(|o| o.get(k)));
+            match v {
+                None => Scalar::Null,
+                Some(v) if v.is_null() => Scalar::Null,
+                Some(v) => {
+                    if let Some(b) = v.as_bool() {
+                        Scalar::Bool(b)
+                    } else if let Some(n) = v.as_f64() {
+                        Scalar::Number(n)
+                    } else if let Some(s) = v.as_str() {
+                        Scalar::Str(s)
+                    } else {
+                        Scalar::Other
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn eval(e: &Expr, body: &Value) -> bool {
+    match *e {
+        Expr::Or(ref es) => es.iter().any(|e| eval(e, body)),
+        Expr::And(ref es) => es.iter().all(|e| eval(e, body)),
+        Expr::Not(ref a) => !eval(a, body),
+        Expr::Truthy(ref o) => {
+            match resolve(o, body) {
+                Scalar::Number(n) => n != 0.0,
+                Scalar::Str(s) => !s.is_empty(),
+                Scalar::Bool(b) => b,
+                Scalar::Null => false,
+                Scalar::Other => true,
+            }
+        }
+        Expr::Compare(ref l, op, ref r) => {
+            let (l, r) = (resolve(l, body), resolve(r, body));
+            let ord = match (&l, &r) {
+                (&Scalar::Number(a), &Scalar::Number(b)) => a.partial_cmp(&b),
+                (&Scalar::Str(a), &Scalar::Str(b)) => Some(a.cmp(b)),
+                _ => None,
+            };
+            let equal = l == r && l != Scalar::Other;
+            match op {
+                Compare::Eq => equal,
+                Compare::Ne => !equal,
+                Compare::Lt => ord.map_or(false, |o| o == Ordering::Less),
+                Compare::Le => ord.map_or(false, |o| o != Ordering::Greater),
+                Compare::Gt => ord.map_or(false, |o| o == Ordering::Greater),
+                Compare::Ge => ord.map_or(false, |o| o != Ordering::Less),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+    use serde_json::Value;
+
+    fn body(s: &str) -> Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source).unwrap().into_iter().map(|(_, t)| t).collect()
+    }
+
+    fn field(name: &str) -> Operand {
+        Operand::Field(name.split('.').map(String::from).collect())
+    }
+
+    fn matches(source: &str, b: &str) -> bool {
+        Filter::parse(source).unwrap().matches(&body(b))
+    }
+
+    #[test]
+    fn tokenizes_operators() {
+        assert_eq!(tokens("== != < <= > >= && || ! ( )"),
+                   vec![Token::Compare(Compare::Eq),
+                        Token::Compare(Compare::Ne),
+                        Token::Compare(Compare::Lt),
+                        Token::Compare(Compare::Le),
+                        Token::Compare(Compare::Gt),
+                        Token::Compare(Compare::Ge),
+                        Token::And,
+                        Token::Or,
+                        Token::Not,
+                        Token::Open,
+                        Token::Close]);
+    }
+
+    #[test]
+    fn tokenizes_literals_and_fields() {
+        assert_eq!(tokens(r#"a.b_c -1.5e3 "x \"y\" \\" true false null"#),
+                   vec![Token::Field(vec!["a".to_string(), "b_c".to_string()]),
+                        Token::Literal(Literal::Number(-1500.0)),
+                        Token::Literal(Literal::Str("x \"y\" \\".to_string())),
+                        Token::Literal(Literal::Bool(true)),
+                        Token::Literal(Literal::Bool(false)),
+                        Token::Literal(Literal::Null)]);
+    }
+
+    #[test]
+    fn tokenizer_reports_positions() {
+        assert_eq!(tokenize(r#"a == "x"#).unwrap_err(),
+                   ParseError {
+                       position: 5,
+                       reason: "unterminated string",
+                   });
+        assert_eq!(tokenize(r#"a == "\n""#).unwrap_err().reason, "invalid escape in string");
+        assert_eq!(tokenize("a = 1").unwrap_err().position, 2);
+        assert_eq!(tokenize("a..b").unwrap_err().reason, "invalid field name");
+        assert_eq!(tokenize("1.2.3").unwrap_err().reason, "invalid number");
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let f = Filter::parse("a || b && c").unwrap();
+        assert_eq!(f.expr,
+                   Expr::Or(vec![Expr::Truthy(field("a")),
+                                 Expr::And(vec![Expr::Truthy(field("b")),
+                                                Expr::Truthy(field("c"))])]));
+        let f = Filter::parse("(a || b) && !c").unwrap();
+        assert_eq!(f.expr,
+                   Expr::And(vec![Expr::Or(vec![Expr::Truthy(field("a")),
+                                                Expr::Truthy(field("b"))]),
+                                  Expr::Not(Box::new(Expr::Truthy(field("c"))))]));
+    }
+
+    #[test]
+    fn parses_comparisons() {
+        let f = Filter::parse(r#"site == "north""#).unwrap();
+        assert_eq!(f.expr,
+                   Expr::Compare(field("site"),
+                                 Compare::Eq,
+                                 Operand::Literal(Literal::Str("north".to_string()))));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for source in &["", "a &&", "(a", "a)", "a b", "a == ", "== 1", "!", "a < < b"] {
+            assert!(Filter::parse(source).is_err(), "{:?} parsed", source);
+        }
+    }
+
+    #[test]
+    fn keeps_chains_flat() {
+        let source = vec!["a"; MAX_TOKENS / 2].join(" && ");
+        match Filter::parse(&source).unwrap().expr {
+            Expr::And(ref es) => assert_eq!(es.len(), MAX_TOKENS / 2),
+            ref e => panic!("parsed as {:?}", e),
+        }
+        assert!(matches(&source, r#"{"a": 1}"#));
+    }
+
+    #[test]
+    fn limits_length_and_depth() {
+        let long = vec!["a"; MAX_TOKENS].join(" && ");
+        assert_eq!(Filter::parse(&long).unwrap_err().reason, "expression too long");
+        let nots: String = (0..MAX_DEPTH).map(|_| '!').collect();
+        assert!(Filter::parse(&format!("{}a", &nots[1..])).is_ok());
+        assert_eq!(Filter::parse(&format!("{}a", nots)).unwrap_err().reason,
+                   "expression nested too deeply");
+        let open: String = (0..MAX_DEPTH).map(|_| '(').collect();
+        let close: String = (0..MAX_DEPTH).map(|_| ')').collect();
+        assert!(Filter::parse(&format!("{}a{}", open, close)).is_err());
+    }
+
+    #[test]
+    fn compares_numbers_and_strings() {
+        let b = r#"{"temperature": 31.5, "site": "north", "reading": {"ok": true}}"#;
+        assert!(matches("temperature > 30", b));
+        assert!(matches("temperature >= 31.5 && temperature <= 31.5", b));
+        assert!(!matches("temperature < 30", b));
+        assert!(matches(r#"site == "north" && site != "south""#, b));
+        assert!(matches(r#"site < "south""#, b));
+        assert!(matches("reading.ok == true", b));
+        assert!(!matches("reading.ok == 1", b));
+    }
+
+    #[test]
+    fn orders_only_like_values() {
+        let b = r#"{"n": 5, "s": "5"}"#;
+        assert!(!matches("s > 4", b));
+        assert!(!matches("s <= 5", b));
+        assert!(!matches("n == \"5\"", b));
+        assert!(matches("n != \"5\"", b));
+    }
+
+    #[test]
+    fn missing_fields_are_null() {
+        let b = r#"{"a": {"b": 1}}"#;
+        assert!(matches("missing == null", b));
+        assert!(matches("a.c == null", b));
+        assert!(matches("a.b.c == null", b));
+        assert!(!matches("missing", b));
+        assert!(!matches("missing > 0", b));
+    }
+
+    #[test]
+    fn fields_alone_test_truthiness() {
+        let b = r#"{"f": false, "z": 0, "e": "", "n": null, "o": {}, "l": [], "t": "x"}"#;
+        for falsy in &["f", "z", "e", "n"] {
+            assert!(!matches(falsy, b), "{} is true", falsy);
+        }
+        for truthy in &["o", "l", "t"] {
+            assert!(matches(truthy, b), "{} is false", truthy);
+        }
+        assert!(matches("!f && !z && (t || f)", b));
+    }
+
+    #[test]
+    fn objects_never_equal() {
+        let b = r#"{"o": {}, "p": {}}"#;
+        assert!(!matches("o == p", b));
+        assert!(matches("o != p", b));
+    }
+}
@@ -1,13 +1,88 @@
 //! Orchestration and task management layer for `unicorn`.
 
+use network::tls::TlsContext;
+use network::token::TokenVerifier;
 use network::websocket::WebSocket;
+use acl::Acl;
 use api;
-use router::{Registry, RouterCommand};
-use schema::config_schema::{Config, Service};
+use datastore::{DataStore, DiskOptions, DiskStore, MemoryStore};
+use router::{Router, RouterCommand};
+use worker::Workers;
+use schema::config_schema::{Config, Service, StorageConfig};
 
-use std::sync::mpsc::channel;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
+
+/// Threads hashing passwords for the account methods. They are kept
+/// apart from the workers of the topic methods, which Argon2 would
+/// otherwise starve.
+const HASHING_THREADS: usize = 2;
+
+/// Account calls waiting for a hashing thread, beyond which they are
+/// refused
+const HASHING_QUEUE: usize = 64;
+
+/// Open the on-disk store `name` under the configured storage path, and
+/// start its housekeeping. Returns `None` if no storage path is set.
+fn open_store(conf: &StorageConfig, name: &str) -> Option<Arc<DataStore>> {
+    let dir = match conf.path {
+        Some(ref p) => Path::new(p).join(name),
+        None => return None,
+    };
+    let opts = DiskOptions { segment_size: conf.segment_size };
+    let store: Arc<DataStore> = match DiskStore::open(&dir, opts) {
+        Ok(s) => Arc::new(s),
+        Err(e) => panic!("Unable to open datastore in {:?}: {}", dir, e),
+    };
+    maintain(store.clone(), conf.maintenance_interval);
+    Some(store)
+}
+
+/// Run housekeeping on `store` every `interval` seconds. An interval of
+/// 0 disables it.
+fn maintain(store: Arc<DataStore>, interval: u64) {
+    if interval == 0 {
+        return;
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+            if let Err(e) = store.maintain() {
+                error!("Datastore maintenance failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Make `socket` accept `wss://` connections if `service` has TLS
+/// configured, reloading its certificate on `SIGHUP`
+fn secure(socket: &mut WebSocket, service: &Service) {
+    let conf = match service.tls {
+        Some(ref c) => c.clone(),
+        None => return,
+    };
+    let tls = match TlsContext::load(conf.clone()) {
+        Ok(t) => t,
+        Err(e) => panic!("Unable to load TLS certificate {}: {}", conf.cert_path, e),
+    };
+    tls.reload_on_hangup();
+    socket.set_tls(tls);
+}
+
+/// Make `socket` authenticate connections by their bearer tokens if
+/// `service` has auth configured
+fn authenticate(socket: &mut WebSocket, service: &Service) {
+    let conf = match service.auth {
+        Some(ref c) => c,
+        None => return,
+    };
+    match TokenVerifier::load(conf) {
+        Ok(v) => socket.set_auth(v),
+        Err(e) => panic!("Unable to set up token authentication: {}", e),
+    }
+}
 
 /// Entry point for `kernel`
 pub fn run(conf: Config) {
@@ -16,26 +91,93 @@ pub fn run(conf: Config) {
     let kernelconf: &Service = &conf.services["api"];
 
     let mut socket = WebSocket::new();
+    secure(&mut socket, kernelconf);
+    authenticate(&mut socket, kernelconf);
 
-    let (tx, rx) = channel::<RouterCommand>();
+    let routerstore = open_store(&conf.storage, "topics");
+    let router = Router::spawn(conf.router.clone(), routerstore);
 
-    thread::spawn(move || {
-        let mut reg = Registry::new();
-        loop {
-            match rx.recv() {
-                Ok(c) => reg.parse_command(c),
-                Err(e) => error!("Error parsing command: {}", e),
-            }
-        }
-    });
+    let acl = match Acl::new(&conf.acl) {
+        Ok(a) => a,
+        Err(e) => panic!("Invalid access control configuration: {}", e),
+    };
 
     // Add topic methods
-    let topicapi = api::topic::TopicAPI::with_tx(Arc::new(Mutex::new(tx.clone())));
-    socket.add_method("topic.create", topicapi.clone().set_type("create"));
-    socket.add_method("topic.subscribe", topicapi.clone().set_type("subscribe"));
-    socket.add_method("topic.publish", topicapi.clone().set_type("publish"));
-    socket.add_method("topic.unsubscribe", topicapi.clone().set_type("unsubscribe"));
+    api::topic::TopicAPI::with_router(router.clone(), Workers::default())
+        .with_acl(acl.clone())
+        .register(&mut socket);
+
+    // Add access control methods
+    api::acl::AclAPI::with_acl(acl.clone()).register(&mut socket);
+
+    // Add session methods
+    api::session::SessionAPI.register(&mut socket);
+
+    // Add account methods
+    let accountstore = open_store(&conf.storage, "accounts").unwrap_or_else(|| {
+        let s: Arc<DataStore> = Arc::new(MemoryStore::new());
+        maintain(s.clone(), conf.storage.maintenance_interval);
+        s
+    });
+    let hashers = Workers::bounded(HASHING_THREADS, HASHING_QUEUE);
+    api::account::AccountAPI::with_store(accountstore, hashers)
+        .with_acl(acl)
+        .register(&mut socket);
+
+    // Drop subscriptions of closed connections
+    let closerouter = Mutex::new(router);
+    socket.on_close(move |id| {
+        if let Ok(r) = closerouter.lock() {
+            let _ = r.send(RouterCommand::Disconnect(id));
+        }
+    });
 
     // Start the listener
     socket.listen(kernelconf.address().as_ref()).unwrap();
 }
+
+/// Entry point for the `datastore` service. Does nothing if it is not
+/// configured.
+pub fn run_datastore(conf: Config) {
+    debug!("Starting datastore...");
+
+    let mut storeconf: Service = match conf.services.get("datastore") {
+        Some(s) => s.clone(),
+        None => {
+            info!("No datastore service configured");
+            return;
+        }
+    };
+
+    // The store has no rights of its own to check, so every connection
+    // must present a token, signed as for the `api` service unless the
+    // datastore has its own key
+    if storeconf.auth.is_none() {
+        storeconf.auth = conf.services.get("api").and_then(|s| s.auth.clone());
+    }
+    match storeconf.auth {
+        Some(ref mut a) => a.required = true,
+        None => warn!("Datastore accepts connections without tokens, as no auth is configured"),
+    }
+
+    let acl = match Acl::new(&conf.acl) {
+        Ok(a) => a,
+        Err(e) => panic!("Invalid access control configuration: {}", e),
+    };
+
+    let store = open_store(&conf.storage, "data").unwrap_or_else(|| {
+        let s: Arc<DataStore> = Arc::new(MemoryStore::new());
+        maintain(s.clone(), conf.storage.maintenance_interval);
+        s
+    });
+
+    let mut socket = WebSocket::new();
+    secure(&mut socket, &storeconf);
+    authenticate(&mut socket, &storeconf);
+
+    // Add datastore methods
+    api::datastore::DataStoreAPI::with_store(store).with_acl(acl).register(&mut socket);
+
+    // Start the listener
+    socket.listen(storeconf.address().as_ref()).unwrap();
+}
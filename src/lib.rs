@@ -18,19 +18,38 @@ extern crate serde_derive;
 
 extern crate serde;
 extern crate serde_json;
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate argon2rs;
+extern crate crypto;
+extern crate rand;
+extern crate rustc_serialize;
 
 #[macro_use]
 extern crate log;
 
 extern crate ws;
 
+#[cfg(feature = "ssl")]
+extern crate libc;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+
+pub mod acl;
 pub mod api;
 pub mod config;
 pub mod datastore;
+pub mod filter;
 pub mod kernel;
 pub mod logger;
 pub mod network;
 pub mod router;
+pub mod worker;
+
+mod util;
+
+#[cfg(test)]
+mod testing;
 
 /// Defines schemas (datatypes) used by API
 #[cfg(feature = "serde_derive")]
@@ -0,0 +1,154 @@
+//! Wire encodings of requests, responses and notifications.
+//!
+//! A client picks the encoding of a connection by offering its name as
+//! a WebSocket subprotocol (`Sec-WebSocket-Protocol`) in the opening
+//! handshake. JSON, the default, travels in text frames; MessagePack
+//! and CBOR travel in binary frames. Whatever the encoding, the
+//! envelopes are the JSON-RPC 2.0 objects of `message_schema`.
+
+use ws::Message;
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+use rmp_serde;
+use serde_cbor;
+
+/// Encoding of the envelopes exchanged on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
+}
+
+impl Encoding {
+    /// Encoding named by a subprotocol offered in the handshake
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "unicorn.json" => Some(Encoding::Json),
+            "unicorn.msgpack" => Some(Encoding::MessagePack),
+            "unicorn.cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Subprotocol name to accept the encoding with
+    pub fn protocol(&self) -> &'static str {
+        match *self {
+            Encoding::Json => "unicorn.json",
+            Encoding::MessagePack => "unicorn.msgpack",
+            Encoding::Cbor => "unicorn.cbor",
+        }
+    }
+
+    /// Read an envelope out of a frame. Returns `None` if the frame is
+    /// malformed, or of the wrong type for the encoding.
+    pub fn decode(&self, m: &Message) -> Option<Value> {
+        let res = match (*self, m) {
+            (Encoding::Json, &Message::Text(ref t)) => {
+                serde_json::from_str::<Value>(t).map_err(|e| e.to_string())
+            }
+            (Encoding::MessagePack, &Message::Binary(ref b)) => {
+                rmp_serde::from_slice::<Value>(b).map_err(|e| e.to_string())
+            }
+            (Encoding::Cbor, &Message::Binary(ref b)) => {
+                serde_cbor::from_slice::<Value>(b).map_err(|e| e.to_string())
+            }
+            _ => Err(String::from("unexpected frame type")),
+        };
+        match res {
+            Ok(v) => Some(v),
+            Err(e) => {
+                debug!("[encoding] Unable to decode {:?} frame: {}", self, e);
+                None
+            }
+        }
+    }
+
+    /// Write an envelope into a frame
+    pub fn encode<T: Serialize>(&self, v: &T) -> Option<Message> {
+        // Binary encodings go through `Value`, so that structs are written
+        // as maps, just as they are in JSON
+        let res = match *self {
+            Encoding::Json => {
+                serde_json::to_string(v)
+                    .map(Message::Text)
+                    .map_err(|e| e.to_string())
+            }
+            Encoding::MessagePack => {
+                rmp_serde::to_vec(&serde_json::to_value(v))
+                    .map(Message::Binary)
+                    .map_err(|e| e.to_string())
+            }
+            Encoding::Cbor => {
+                serde_cbor::to_vec(&serde_json::to_value(v))
+                    .map(Message::Binary)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match res {
+            Ok(m) => Some(m),
+            Err(e) => {
+                error!("[encoding] Unable to encode {:?} frame: {}", self, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use schema::message_schema::{MessageResponse, ResponseError};
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    #[test]
+    fn round_trips_every_encoding() {
+        let request: Value = serde_json::from_str(r#"{"jsonrpc": "2.0", "id": 7,
+            "method": "topic.publish",
+            "params": {"topic_id": "a/b", "message": "h\u00e9llo", "ttl_ms": 1000,
+                       "retain": true, "tags": [1, -2, 3.5, null]}}"#)
+            .unwrap();
+        for e in &ENCODINGS {
+            let frame = e.encode(&request).unwrap();
+            assert_eq!(e.decode(&frame), Some(request.clone()), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn writes_structs_as_maps() {
+        let r = MessageResponse::error(Value::String("x".to_string()),
+                                       ResponseError::NotFound);
+        let expected = serde_json::to_value(&r);
+        for e in &ENCODINGS {
+            let frame = e.encode(&r).unwrap();
+            assert_eq!(e.decode(&frame), Some(expected.clone()), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn refuses_frames_of_the_wrong_type() {
+        let v = serde_json::from_str::<Value>(r#"{"id": 1}"#).unwrap();
+        let text = Encoding::Json.encode(&v).unwrap();
+        let binary = Encoding::MessagePack.encode(&v).unwrap();
+        assert!(Encoding::Json.decode(&binary).is_none());
+        assert!(Encoding::MessagePack.decode(&text).is_none());
+        assert!(Encoding::Cbor.decode(&text).is_none());
+    }
+
+    #[test]
+    fn names_every_encoding_by_its_protocol() {
+        for e in &ENCODINGS {
+            assert_eq!(Encoding::from_protocol(e.protocol()), Some(*e));
+        }
+        assert!(Encoding::from_protocol("unicorn.xml").is_none());
+    }
+}
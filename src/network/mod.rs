@@ -1,3 +1,6 @@
 //! Network layer for `unicorn`.
 
+pub mod encoding;
+pub mod tls;
+pub mod token;
 pub mod websocket;
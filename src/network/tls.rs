@@ -0,0 +1,243 @@
+//! TLS for `wss://` listeners.
+//!
+//! The certificate chain and private key are read from the PEM files
+//! named in the `TlsConfig` of a service. They can be replaced while the
+//! server runs: on `SIGHUP` the files of every listener are read again,
+//! and connections accepted from then on use the new ones. If they fail
+//! to load, the previous ones are kept.
+//!
+//! SSLv2 and SSLv3 are refused; connections use TLS.
+//!
+//! TLS needs the `ssl` feature. Without it, `TlsContext` cannot be
+//! loaded, and listeners only accept plain `ws://` connections.
+
+pub use self::imp::TlsContext;
+
+#[cfg(feature = "ssl")]
+mod imp {
+    use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, Ssl, SslContext, SslMethod};
+    use openssl::ssl::error::SslError;
+    use openssl::x509::X509FileType;
+    use ws;
+
+    use std::io;
+    use std::sync::{Arc, RwLock};
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use schema::config_schema::TlsConfig;
+
+    /// Number of `SIGHUP`s the process got. Each listener reloads its
+    /// certificates when it sees the count change.
+    static HANGUPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Milliseconds between checks for a `SIGHUP`
+    const HANGUP_CHECK_MS: u64 = 500;
+
+    /// Certificates of a listener, shared by all of its connections
+    #[derive(Clone)]
+    pub struct TlsContext {
+        conf: TlsConfig,
+        current: Arc<RwLock<Arc<SslContext>>>,
+    }
+
+    impl TlsContext {
+        /// Load the certificate chain and key named in `conf`
+        pub fn load(conf: TlsConfig) -> io::Result<Self> {
+            let ctx = try!(build_context(&conf));
+            Ok(TlsContext {
+                conf: conf,
+                current: Arc::new(RwLock::new(Arc::new(ctx))),
+            })
+        }
+
+        /// Read the certificate chain and key again. On failure the
+        /// ones in use are kept.
+        pub fn reload(&self) -> io::Result<()> {
+            let ctx = Arc::new(try!(build_context(&self.conf)));
+            match self.current.write() {
+                Ok(mut c) => *c = ctx,
+                Err(p) => *p.into_inner() = ctx,
+            }
+            Ok(())
+        }
+
+        /// Session for a new connection
+        pub fn ssl(&self) -> ws::Result<Ssl> {
+            let ctx = match self.current.read() {
+                Ok(c) => c.clone(),
+                Err(p) => p.into_inner().clone(),
+            };
+            Ssl::new(&ctx).map_err(ws::Error::from)
+        }
+
+        /// Reload the certificates whenever the process gets a `SIGHUP`
+        pub fn reload_on_hangup(&self) {
+            let handler: extern "C" fn(::libc::c_int) = on_hangup;
+            unsafe {
+                ::libc::signal(::libc::SIGHUP, handler as ::libc::sighandler_t);
+            }
+            let tls = self.clone();
+            let mut seen = HANGUPS.load(Ordering::SeqCst);
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_millis(HANGUP_CHECK_MS));
+                    if !hung_up_since(&mut seen) {
+                        continue;
+                    }
+                    match tls.reload() {
+                        Ok(()) => info!("[tls] Reloaded certificate {}", tls.conf.cert_path),
+                        Err(e) => {
+                            error!("[tls] Unable to reload certificate {}: {}",
+                                   tls.conf.cert_path,
+                                   e)
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    extern "C" fn on_hangup(_: ::libc::c_int) {
+        HANGUPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Check if the process got a `SIGHUP` since the count was `seen`,
+    /// and catch up with it
+    fn hung_up_since(seen: &mut usize) -> bool {
+        let now = HANGUPS.load(Ordering::SeqCst);
+        if now == *seen {
+            return false;
+        }
+        *seen = now;
+        true
+    }
+
+    fn build_context(conf: &TlsConfig) -> io::Result<SslContext> {
+        let build = || -> Result<SslContext, SslError> {
+            let mut ctx = try!(SslContext::new(SslMethod::Sslv23));
+            ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+            try!(ctx.set_certificate_chain_file(&conf.cert_path, X509FileType::PEM));
+            try!(ctx.set_private_key_file(&conf.key_path, X509FileType::PEM));
+            try!(ctx.check_private_key());
+            Ok(ctx)
+        };
+        build().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::{HANGUPS, hung_up_since, on_hangup};
+
+        use openssl::crypto::hash::Type;
+        use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+        use openssl::x509::X509Generator;
+
+        use std::fs::File;
+        use std::path::PathBuf;
+        use std::sync::atomic::Ordering;
+
+        use schema::config_schema::TlsConfig;
+        use testing::scratch;
+
+        /// Write a new self-signed certificate and its key in `dir`
+        fn self_signed(dir: &PathBuf, cert: &str, key: &str) -> TlsConfig {
+            let (x509, pkey) = X509Generator::new()
+                .set_bitlength(2048)
+                .set_valid_period(1)
+                .add_name("CN".to_string(), "localhost".to_string())
+                .set_sign_hash(Type::SHA256)
+                .generate()
+                .unwrap();
+            let conf = TlsConfig {
+                cert_path: dir.join(cert).to_string_lossy().into_owned(),
+                key_path: dir.join(key).to_string_lossy().into_owned(),
+            };
+            x509.write_pem(&mut File::create(&conf.cert_path).unwrap()).unwrap();
+            pkey.write_pem(&mut File::create(&conf.key_path).unwrap()).unwrap();
+            conf
+        }
+
+        #[test]
+        fn loads_self_signed_certificates() {
+            let dir = scratch("tls-load");
+            let tls = TlsContext::load(self_signed(&dir, "cert.pem", "key.pem")).unwrap();
+            assert!(tls.ssl().is_ok());
+        }
+
+        #[test]
+        fn refuses_old_protocols() {
+            let dir = scratch("tls-protocols");
+            let mut ctx = build_context(&self_signed(&dir, "cert.pem", "key.pem")).unwrap();
+            assert!(ctx.get_options().contains(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3));
+        }
+
+        #[test]
+        fn refuses_mismatched_keys() {
+            let dir = scratch("tls-mismatch");
+            let a = self_signed(&dir, "a.pem", "a.key");
+            let b = self_signed(&dir, "b.pem", "b.key");
+            let conf = TlsConfig {
+                cert_path: a.cert_path,
+                key_path: b.key_path,
+            };
+            assert!(TlsContext::load(conf).is_err());
+        }
+
+        #[test]
+        fn reloads_replaced_certificates() {
+            let dir = scratch("tls-reload");
+            let conf = self_signed(&dir, "cert.pem", "key.pem");
+            let tls = TlsContext::load(conf.clone()).unwrap();
+
+            // New files are picked up
+            self_signed(&dir, "cert.pem", "key.pem");
+            tls.reload().unwrap();
+            assert!(tls.ssl().is_ok());
+
+            // Broken files are not, and the certificate in use is kept
+            File::create(&conf.key_path).unwrap();
+            assert!(tls.reload().is_err());
+            assert!(tls.ssl().is_ok());
+        }
+
+        #[test]
+        fn every_watcher_sees_a_hangup() {
+            let mut first = HANGUPS.load(Ordering::SeqCst);
+            let mut second = first;
+            on_hangup(::libc::SIGHUP);
+            assert!(hung_up_since(&mut first));
+            assert!(hung_up_since(&mut second));
+            assert!(!hung_up_since(&mut first));
+            assert!(!hung_up_since(&mut second));
+        }
+    }
+}
+
+#[cfg(not(feature = "ssl"))]
+mod imp {
+    use std::io;
+
+    use schema::config_schema::TlsConfig;
+
+    /// Stands in for the certificates of a listener when built without
+    /// the `ssl` feature. It has no values, so it is never used.
+    #[derive(Clone)]
+    pub enum TlsContext {}
+
+    impl TlsContext {
+        pub fn load(_: TlsConfig) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Other, "built without the ssl feature"))
+        }
+
+        pub fn reload(&self) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn reload_on_hangup(&self) {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Bearer token authentication for the opening handshake.
+//!
+//! Clients authenticate by presenting a JSON Web Token, either in an
+//! `Authorization: Bearer <token>` header or as the `access_token`
+//! query parameter of the resource they connect to. Tokens are signed
+//! with HMAC-SHA256 (`HS256`) using a shared secret, or with RSA
+//! (`RS256`) checked against a public key; the latter needs the `ssl`
+//! feature.
+//!
+//! A token is accepted if its signature holds, it names its subject in
+//! `sub`, and the current time is within its `nbf` and `exp` claims,
+//! give or take the configured leeway. Tokens without `exp` are refused
+//! unless configured otherwise. The connection is then
+//! authenticated as the subject, with all the claims of the token.
+//! Connections presenting a token that is not accepted are refused.
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use rustc_serialize::base64::FromBase64;
+use serde_json::{self, Value};
+use ws::Request;
+
+use std::error;
+use std::fmt;
+#[cfg(feature = "ssl")]
+use std::fs::File;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "ssl")]
+use std::sync::Arc;
+
+#[cfg(feature = "ssl")]
+use openssl::crypto::hash::{self, Type};
+#[cfg(feature = "ssl")]
+use openssl::crypto::pkey::PKey;
+
+use network::websocket::Identity;
+use schema::config_schema::AuthConfig;
+
+/// Query parameter a token can be given in
+const QUERY_PARAMETER: &'static str = "access_token";
+
+/// Why a token was not accepted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenError {
+    /// No token was presented, but one is required
+    Missing,
+    /// Not a JSON Web Token
+    Malformed,
+    /// Signed with an algorithm other than the configured one
+    Algorithm,
+    /// Signature does not match
+    Signature,
+    /// Past its `exp` time
+    Expired,
+    /// No `exp` claim, and tokens that never expire are not accepted
+    NoExpiry,
+    /// Before its `nbf` time
+    NotYetValid,
+    /// No `sub` claim naming who it was issued to
+    NoSubject,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for TokenError {
+    fn description(&self) -> &str {
+        match *self {
+            TokenError::Missing => "no token",
+            TokenError::Malformed => "malformed token",
+            TokenError::Algorithm => "unexpected signing algorithm",
+            TokenError::Signature => "invalid signature",
+            TokenError::Expired => "token expired",
+            TokenError::NoExpiry => "token has no expiry",
+            TokenError::NotYetValid => "token not yet valid",
+            TokenError::NoSubject => "token has no subject",
+        }
+    }
+}
+
+/// Key tokens are signed with
+#[derive(Clone)]
+enum Key {
+    Hmac(Vec<u8>),
+    #[cfg(feature = "ssl")]
+    Rsa(Arc<PKey>),
+}
+
+impl Key {
+    /// Name of the algorithm, as in the `alg` of a token header
+    fn algorithm(&self) -> &'static str {
+        match *self {
+            Key::Hmac(_) => "HS256",
+            #[cfg(feature = "ssl")]
+            Key::Rsa(_) => "RS256",
+        }
+    }
+
+    fn verify(&self, input: &[u8], signature: &[u8]) -> bool {
+        match *self {
+            // Compared in constant time, so that the time taken does not
+            // tell how much of a signature was right
+            Key::Hmac(ref secret) => hmac_sha256(secret, input) == MacResult::new(signature),
+            #[cfg(feature = "ssl")]
+            Key::Rsa(ref key) => {
+                key.verify_with_hash(&hash::hash(Type::SHA256, input), signature, Type::SHA256)
+            }
+        }
+    }
+}
+
+/// Checks the tokens presented by connections to a listener
+#[derive(Clone)]
+pub struct TokenVerifier {
+    key: Key,
+    required: bool,
+    leeway_s: u64,
+    allow_without_exp: bool,
+}
+
+impl TokenVerifier {
+    /// Set up the key named in `conf`, reading it from its file if it
+    /// is a public key
+    pub fn load(conf: &AuthConfig) -> io::Result<Self> {
+        let key = match (&conf.secret, &conf.public_key_path) {
+            (&Some(ref s), &None) => Key::Hmac(s.clone().into_bytes()),
+            (&None, &Some(ref p)) => try!(rsa_key(p)),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "exactly one of secret and public_key_path must be set"))
+            }
+        };
+        Ok(TokenVerifier {
+            key: key,
+            required: conf.required,
+            leeway_s: conf.leeway_s,
+            allow_without_exp: conf.allow_without_exp,
+        })
+    }
+
+    /// Check the token presented in the handshake `req`. Returns the
+    /// identity it grants, or `None` if there is no token and none is
+    /// required.
+    pub fn authenticate(&self, req: &Request) -> Result<Option<Identity>, TokenError> {
+        match bearer_token(req) {
+            Some(t) => self.verify(&t).map(Some),
+            None if self.required => Err(TokenError::Missing),
+            None => Ok(None),
+        }
+    }
+
+    /// Check `token`, returning the identity it grants
+    pub fn verify(&self, token: &str) -> Result<Identity, TokenError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(TokenError::Malformed);
+        }
+        let header = try!(decode_json(parts[0]));
+        let alg = header.as_object().and_then(|h| h.get("alg")).and_then(|a| a.as_str());
+        if alg != Some(self.key.algorithm()) {
+            return Err(TokenError::Algorithm);
+        }
+        let signature = try!(base64url_decode(parts[2]).ok_or(TokenError::Malformed));
+        let input = &token[..parts[0].len() + 1 + parts[1].len()];
+        if !self.key.verify(input.as_bytes(), &signature) {
+            return Err(TokenError::Signature);
+        }
+
+        let claims = try!(decode_json(parts[1]));
+        let now = now_secs();
+        let leeway = self.leeway_s as f64;
+        match try!(numeric_claim(&claims, "exp")) {
+            Some(exp) if now > exp + leeway => return Err(TokenError::Expired),
+            Some(_) => {}
+            None if !self.allow_without_exp => return Err(TokenError::NoExpiry),
+            None => {}
+        }
+        if let Some(nbf) = try!(numeric_claim(&claims, "nbf")) {
+            if now + leeway < nbf {
+                return Err(TokenError::NotYetValid);
+            }
+        }
+        let subject = claims.as_object().and_then(|c| c.get("sub")).and_then(|s| s.as_str());
+        let subject = match subject {
+            Some(s) if !s.is_empty() => s.to_string(),
+            _ => return Err(TokenError::NoSubject),
+        };
+        Ok(Identity::token(subject, claims))
+    }
+}
+
+#[cfg(feature = "ssl")]
+fn rsa_key(path: &str) -> io::Result<Key> {
+    let mut f = try!(File::open(path));
+    PKey::public_rsa_key_from_pem(&mut f)
+        .map(|k| Key::Rsa(Arc::new(k)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "ssl"))]
+fn rsa_key(_: &str) -> io::Result<Key> {
+    Err(io::Error::new(io::ErrorKind::Other, "built without the ssl feature"))
+}
+
+/// Token presented in the `Authorization` header of `req`, or in its
+/// query string
+fn bearer_token(req: &Request) -> Option<String> {
+    if let Some(h) = req.header("Authorization") {
+        let h = String::from_utf8_lossy(h);
+        let mut words = h.split_whitespace();
+        if let (Some(scheme), Some(token)) = (words.next(), words.next()) {
+            if scheme.to_lowercase() == "bearer" {
+                return Some(token.to_string());
+            }
+        }
+    }
+    let query = match req.resource().find('?') {
+        Some(i) => &req.resource()[i + 1..],
+        None => return None,
+    };
+    query.split('&')
+        .filter_map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == QUERY_PARAMETER && !v.is_empty() => Some(v.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn now_secs() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0,
+    }
+}
+
+/// Claim `name`, which must be a number if it is there
+fn numeric_claim(claims: &Value, name: &str) -> Result<Option<f64>, TokenError> {
+    match claims.as_object().and_then(|c| c.get(name)) {
+        None => Ok(None),
+        Some(v) => v.as_f64().map(Some).ok_or(TokenError::Malformed),
+    }
+}
+
+/// JSON object in a base64url encoded part of a token
+fn decode_json(part: &str) -> Result<Value, TokenError> {
+    let bytes = try!(base64url_decode(part).ok_or(TokenError::Malformed));
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(v) => {
+            if v.is_object() {
+                Ok(v)
+            } else {
+                Err(TokenError::Malformed)
+            }
+        }
+        Err(_) => Err(TokenError::Malformed),
+    }
+}
+
+/// Decode base64 with the URL and filename safe alphabet, padded or not
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    // `from_base64` also takes the standard alphabet, and skips line breaks
+    if s.contains(|c| c == '+' || c == '/' || c == '\r' || c == '\n') {
+        return None;
+    }
+    s.from_base64().ok()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> MacResult {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    mac.result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    use schema::config_schema::AuthConfig;
+
+    /// Base64url without padding, as in tokens
+    fn encode(bytes: &[u8]) -> String {
+        bytes.to_base64(URL_SAFE)
+    }
+
+    fn verifier(allow_without_exp: bool) -> TokenVerifier {
+        TokenVerifier::load(&AuthConfig {
+                secret: Some("secret".to_string()),
+                public_key_path: None,
+                required: true,
+                leeway_s: 0,
+                allow_without_exp: allow_without_exp,
+            })
+            .unwrap()
+    }
+
+    fn token(header: &str, claims: &str, secret: &[u8]) -> String {
+        let input = format!("{}.{}", encode(header.as_bytes()), encode(claims.as_bytes()));
+        let signature = encode(hmac_sha256(secret, input.as_bytes()).code());
+        format!("{}.{}", input, signature)
+    }
+
+    fn hs256(claims: &str) -> String {
+        token(r#"{"alg":"HS256","typ":"JWT"}"#, claims, b"secret")
+    }
+
+    #[test]
+    fn decodes_base64url() {
+        assert_eq!(base64url_decode(""), Some(vec![]));
+        assert_eq!(base64url_decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(base64url_decode("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(base64url_decode("Zm8"), Some(b"fo".to_vec()));
+        assert_eq!(base64url_decode("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(base64url_decode("-_-_"), Some(vec![0xfb, 0xff, 0xbf]));
+        assert_eq!(base64url_decode("+/+/"), None);
+        assert_eq!(base64url_decode("Zm 9v"), None);
+    }
+
+    #[test]
+    fn accepts_valid_tokens() {
+        let exp = now_secs() as u64 + 60;
+        let t = hs256(&format!(r#"{{"sub":"ann","exp":{},"role":"ops"}}"#, exp));
+        let identity = verifier(false).verify(&t).unwrap();
+        assert_eq!(identity.id, "ann");
+        assert_eq!(identity.name(), "token:ann");
+        assert_eq!(identity.claims.as_object().and_then(|c| c.get("role")).and_then(|r| r.as_str()),
+                   Some("ops"));
+    }
+
+    #[test]
+    fn refuses_tokens_without_expiry_unless_allowed() {
+        let t = hs256(r#"{"sub":"ann"}"#);
+        assert_eq!(verifier(false).verify(&t).unwrap_err(), TokenError::NoExpiry);
+        assert_eq!(verifier(true).verify(&t).unwrap().id, "ann");
+    }
+
+    #[test]
+    fn refuses_invalid_tokens() {
+        let now = now_secs() as u64;
+        let v = verifier(true);
+        let claims = format!(r#"{{"sub":"ann","exp":{}}}"#, now + 60);
+        assert_eq!(v.verify(&token(r#"{"alg":"HS256"}"#, &claims, b"other")).unwrap_err(),
+                   TokenError::Signature);
+        assert_eq!(v.verify(&token(r#"{"alg":"none"}"#, &claims, b"secret")).unwrap_err(),
+                   TokenError::Algorithm);
+        assert_eq!(v.verify(&hs256(&format!(r#"{{"sub":"ann","exp":{}}}"#, now - 10)))
+                       .unwrap_err(),
+                   TokenError::Expired);
+        assert_eq!(v.verify(&hs256(&format!(r#"{{"sub":"ann","nbf":{}}}"#, now + 60)))
+                       .unwrap_err(),
+                   TokenError::NotYetValid);
+        assert_eq!(v.verify(&hs256(r#"{"exp":"soon","sub":"ann"}"#)).unwrap_err(),
+                   TokenError::Malformed);
+        assert_eq!(v.verify(&hs256(r#"{"sub":""}"#)).unwrap_err(), TokenError::NoSubject);
+        assert_eq!(v.verify("a.b").unwrap_err(), TokenError::Malformed);
+        let mut t = hs256(&claims);
+        t.push('A');
+        assert_eq!(v.verify(&t).unwrap_err(), TokenError::Signature);
+    }
+}
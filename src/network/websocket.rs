@@ -1,52 +1,487 @@
 //! `WebSocket` implementation for unicorn.
+//!
+//! Frames carry JSON-RPC 2.0 requests, either one request object or a
+//! batch of them as an array, in the `Encoding` negotiated for the
+//! connection. Each request is dispatched to the `APIHandlerCommand`
+//! registered for its method.
 
 use ws::{WebSocket as WS, Factory, Sender, Handler, Result, Message, Handshake, CloseCode};
-use serde_json;
+use ws::{Builder, Request, Response, Settings};
+use serde_json::Value;
+#[cfg(feature = "ssl")]
+use openssl::ssl::Ssl;
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
 use std::collections::HashMap;
 use std::clone::Clone;
 
-use schema::message_schema::{MessageRequest, MessageResponse};
+use network::encoding::Encoding;
+use network::tls::TlsContext;
+use network::token::{TokenError, TokenVerifier};
+use schema::message_schema::{BINARY_MARKER, JSONRPC_VERSION, MessageRequest,
+                             MessageRequestBinary, MessageResponse, ResponseError};
 
-/// Trait to implement handling of Sender
-pub trait APIHandlerCommand {
-    fn execute(&mut self, ws: Sender, msg: Message) -> Option<MessageResponse>;
+/// Prefix of the session ids of connections
+pub const SESSION_PREFIX: &'static str = "_conn/";
+
+/// Frames each connection can have queued for sending on average. The
+/// queue is shared by every connection of a socket. Sending to a
+/// connection fails while it is full, which costs the connection its
+/// subscriptions.
+const QUEUE_SIZE: usize = 1024;
+
+/// How a connection authenticated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentitySource {
+    /// Logged in to an account with its password
+    Account,
+    /// Presented a token in the opening handshake
+    Token,
+}
+
+impl IdentitySource {
+    /// Prefix of the names of identities from this source
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            IdentitySource::Account => "account:",
+            IdentitySource::Token => "token:",
+        }
+    }
+}
+
+/// Who a connection is authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub source: IdentitySource,
+    /// Id of the account, or subject of the token
+    pub id: String,
+    /// Claims of the token the connection presented, `Null` if it
+    /// logged in with a password
+    pub claims: Value,
+}
+
+impl Identity {
+    /// Identity of a connection logged in to the account `id`
+    pub fn account(id: String) -> Self {
+        Identity {
+            source: IdentitySource::Account,
+            id: id,
+            claims: Value::Null,
+        }
+    }
+
+    /// Identity of a connection that presented a token for `subject`
+    pub fn token(subject: String, claims: Value) -> Self {
+        Identity {
+            source: IdentitySource::Token,
+            id: subject,
+            claims: claims,
+        }
+    }
+
+    /// Id qualified by the source, `account:{id}` or `token:{subject}`,
+    /// so that an account cannot pass for the subject of a token with
+    /// the same id, or the other way around
+    pub fn name(&self) -> String {
+        format!("{}{}", self.source.prefix(), self.id)
+    }
+}
+
+/// A client connection, as seen by the handlers of its calls
+#[derive(Clone)]
+pub struct Connection {
+    pub id: u64,
+    pub sender: Sender,
+    /// Encoding of everything sent on the connection
+    pub encoding: Encoding,
+    /// Identity the connection authenticated as. Copies of a connection
+    /// share it, so that it holds for all later calls.
+    identity: Arc<RwLock<Option<Identity>>>,
+}
+
+impl Connection {
+    pub fn new(id: u64, sender: Sender) -> Self {
+        Connection {
+            id: id,
+            sender: sender,
+            encoding: Encoding::default(),
+            identity: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Identity the connection authenticated as, if any
+    pub fn identity(&self) -> Option<Identity> {
+        match self.identity.read() {
+            Ok(i) => i.clone(),
+            Err(p) => p.into_inner().clone(),
+        }
+    }
+
+    /// Id the connection subscribes under, made from the connection id
+    /// so that it stays the same for as long as the connection lasts,
+    /// whoever it authenticates as
+    pub fn session_id(&self) -> String {
+        format!("{}{}", SESSION_PREFIX, self.id)
+    }
+
+    /// Id the messages the connection publishes carry: the name of its
+    /// identity, or its session id if it has none
+    pub fn publisher_id(&self) -> String {
+        match self.identity() {
+            Some(i) => i.name(),
+            None => self.session_id(),
+        }
+    }
+
+    /// Authenticate the connection as `identity`, replacing any earlier
+    /// one
+    pub fn authenticate(&self, identity: Identity) {
+        match self.identity.write() {
+            Ok(mut i) => *i = Some(identity),
+            Err(p) => *p.into_inner() = Some(identity),
+        }
+    }
+}
+
+/// Trait to implement handling of Sender. Every connection gets its own
+/// clone of each handler, but the calls of all the connections of a
+/// socket are handled one at a time, on its event loop thread, so
+/// handlers hand slow work over to `Workers`.
+pub trait APIHandlerCommand: CommandClone + Send {
+    /// Handle a call with `params` (`Null` if none were given) received
+    /// on `conn`. Returns the result of the call.
+    fn execute(&mut self,
+               conn: &Connection,
+               params: Value)
+               -> ::std::result::Result<Value, ResponseError>;
+
+    /// Handle a call made with a binary frame, `payload` being the raw
+    /// bytes that followed the request. Methods that take no binary
+    /// payload keep the default, which rejects the request.
+    fn execute_binary(&mut self,
+                      _conn: &Connection,
+                      _params: Value,
+                      _payload: Vec<u8>)
+                      -> ::std::result::Result<Value, ResponseError> {
+        Err(ResponseError::InvalidRequest)
+    }
+
+    /// Whether calls are answered through `execute_deferred` rather than
+    /// `execute`
+    fn is_deferred(&self) -> bool {
+        false
+    }
+
+    /// Handle a call answered later, by sending the result through
+    /// `reply`. An error returned here is sent as the response right
+    /// away, and `reply` is then never used.
+    fn execute_deferred(&mut self,
+                        _conn: &Connection,
+                        _params: Value,
+                        _reply: Responder)
+                        -> ::std::result::Result<(), ResponseError> {
+        Err(ResponseError::MethodNotFound)
+    }
+}
+
+/// Sends the response to a call answered after its handler returned
+#[derive(Clone)]
+pub struct Responder {
+    sender: Sender,
+    encoding: Encoding,
+    /// Id of the call, `None` for notifications
+    id: Option<Value>,
+    /// Place of the call in its batch, if it was made in one
+    slot: Option<Arc<Slot>>,
+}
+
+impl Responder {
+    pub fn new(conn: &Connection, id: Option<Value>) -> Self {
+        Responder {
+            sender: conn.sender.clone(),
+            encoding: conn.encoding,
+            id: id,
+            slot: None,
+        }
+    }
+
+    /// Send the response to the call. Notifications get none. For calls
+    /// made in a batch, it waits for the responses to the others.
+    pub fn respond(&self, res: ::std::result::Result<Value, ResponseError>) {
+        let r = self.id.clone().map(|id| {
+            match res {
+                Ok(v) => MessageResponse::success(id, v),
+                Err(e) => MessageResponse::error(id, e),
+            }
+        });
+        match (r, self.slot.as_ref()) {
+            (r, Some(slot)) => slot.answer(r),
+            (Some(r), None) => {
+                if let Some(f) = self.encoding.encode(&r) {
+                    let _ = self.sender.send(f);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+/// Responses to the calls of a batch, sent together once the last call
+/// is answered
+struct Batch {
+    sender: Sender,
+    encoding: Encoding,
+    /// One per call, in the order of the batch. `None` until answered,
+    /// and for notifications.
+    responses: Vec<Option<MessageResponse>>,
+    answered: Vec<bool>,
+    /// Calls not answered yet, plus one while the batch is dispatched
+    outstanding: usize,
+}
+
+impl Batch {
+    fn new(conn: &Connection, calls: usize) -> Self {
+        Batch {
+            sender: conn.sender.clone(),
+            encoding: conn.encoding,
+            responses: vec![None; calls],
+            answered: vec![false; calls],
+            outstanding: calls + 1,
+        }
+    }
+
+    /// Record the answer to call `index`, `None` for notifications. Later
+    /// answers to the same call are ignored. Returns the responses once
+    /// the batch is complete.
+    fn answer(&mut self,
+              index: usize,
+              r: Option<MessageResponse>)
+              -> Option<Vec<MessageResponse>> {
+        if self.answered[index] {
+            return None;
+        }
+        self.answered[index] = true;
+        self.responses[index] = r;
+        self.release()
+    }
+
+    /// Count off one answer, or the end of dispatching. Returns the
+    /// responses if nothing else is outstanding.
+    fn release(&mut self) -> Option<Vec<MessageResponse>> {
+        self.outstanding -= 1;
+        if self.outstanding > 0 {
+            return None;
+        }
+        Some(self.responses.drain(..).filter_map(|r| r).collect())
+    }
+}
+
+fn lock(batch: &Mutex<Batch>) -> ::std::sync::MutexGuard<Batch> {
+    batch.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Place of a call in its batch. A call whose slot is dropped before it
+/// is answered counts as answered with no response.
+struct Slot {
+    batch: Arc<Mutex<Batch>>,
+    index: usize,
+}
+
+impl Slot {
+    /// Record the answer to the call, and send the responses to the
+    /// batch if it was the last. A batch of notifications gets no reply.
+    fn answer(&self, r: Option<MessageResponse>) {
+        let mut batch = lock(&self.batch);
+        match batch.answer(self.index, r) {
+            Some(ref responses) if !responses.is_empty() => {
+                if let Some(f) = batch.encoding.encode(responses) {
+                    let _ = batch.sender.send(f);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.answer(None);
+    }
+}
+
+/// Callback invoked with the connection id whenever a connection closes
+pub type CloseHook = Arc<Fn(u64) + Send + Sync>;
+
+/// Reply to a single request or to a batch
+pub enum Reply {
+    Single(MessageResponse),
+    Batch(Vec<MessageResponse>),
+}
+
+impl Reply {
+    pub fn encode(&self, encoding: Encoding) -> Option<Message> {
+        match *self {
+            Reply::Single(ref r) => encoding.encode(r),
+            Reply::Batch(ref b) => encoding.encode(b),
+        }
+    }
+}
+
+/// Boxed `APIHandlerCommand`, so that handlers of different types can
+/// be registered on the same socket
+pub type BoxedCommand = Box<APIHandlerCommand>;
+
+/// Cloning of boxed handlers. Implemented for every `APIHandlerCommand`
+/// that is `Clone`; the copy holds the per-connection state.
+pub trait CommandClone {
+    fn clone_command(&self) -> BoxedCommand;
+}
+
+impl<T> CommandClone for T
+    where T: APIHandlerCommand + Clone + 'static
+{
+    fn clone_command(&self) -> BoxedCommand {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for BoxedCommand {
+    fn clone(&self) -> Self {
+        self.clone_command()
+    }
 }
 
 /// Handler for websocket
-#[derive(Debug, Clone)]
-pub struct APIHandler<'a, H: APIHandlerCommand + 'static> {
-    handlers: HashMap<&'a str, H>,
+#[derive(Clone)]
+pub struct APIHandler<'a> {
+    handlers: HashMap<&'a str, BoxedCommand>,
 }
 
-impl<'a, H: APIHandlerCommand + 'static> APIHandler<'a, H> {
+impl<'a> APIHandler<'a> {
     pub fn new() -> Self {
         APIHandler { handlers: HashMap::new() }
     }
 
-    pub fn add_handler(&mut self, id: &'a str, handler: H) {
+    pub fn add_handler(&mut self, id: &'a str, handler: BoxedCommand) {
         self.handlers.insert(id, handler);
     }
 
-    pub fn handle(&mut self, s: Sender, m: Message) -> Option<MessageResponse> {
-        if m.is_text() {
-            let req = match serde_json::from_str::<MessageRequest>(m.as_text().unwrap_or("")) {
-                Ok(req) => req,
-                Err(e) => {
-                    error!("{:}", e);
-                    return Some(MessageResponse::error("unicorn.error", "InvalidRequest"));
+    /// Answer the request or batch in `m`. On JSON connections, binary
+    /// frames carry a single request laid out as `MessageRequestBinary`;
+    /// on MessagePack and CBOR ones, such frames start with
+    /// `BINARY_MARKER`. Either way they are answered like any other call.
+    pub fn handle(&mut self, conn: &Connection, m: Message) -> Option<Reply> {
+        let v = match (conn.encoding, m) {
+            (Encoding::Json, Message::Binary(b)) => {
+                return self.call_binary(conn, b);
+            }
+            (_, Message::Binary(ref b)) if b.first() == Some(&BINARY_MARKER) => {
+                return self.call_binary(conn, b[1..].to_vec());
+            }
+            (e, m) => {
+                match e.decode(&m) {
+                    Some(v) => v,
+                    None => {
+                        let r = MessageResponse::error(Value::Null, ResponseError::ParseError);
+                        return Some(Reply::Single(r));
+                    }
+                }
+            }
+        };
+
+        match v {
+            Value::Array(batch) => {
+                if batch.is_empty() {
+                    let r = MessageResponse::error(Value::Null, ResponseError::InvalidRequest);
+                    return Some(Reply::Single(r));
                 }
-            };
-            match self.handlers.get_mut(&req.method[..]) {
-                Some(h) => return h.execute(s, m),
-                None => {
-                    return Some(MessageResponse::error("unicorn.error", "MethodNotFound"));
+                let pending = Arc::new(Mutex::new(Batch::new(conn, batch.len())));
+                for (i, r) in batch.into_iter().enumerate() {
+                    let slot = Arc::new(Slot {
+                        batch: pending.clone(),
+                        index: i,
+                    });
+                    // Deferred calls keep a copy of the slot until they
+                    // are answered; the others are answered here
+                    if let Some(r) = self.call(conn, r, None, Some(slot.clone())) {
+                        slot.answer(Some(r));
+                    }
                 }
+                // Deferred calls still outstanding send the responses
+                // once the last of them is answered. A batch of
+                // notifications gets no reply at all.
+                let res = lock(&pending).release();
+                match res {
+                    Some(ref r) if r.is_empty() => None,
+                    res => res.map(Reply::Batch),
+                }
+            }
+            v => self.call(conn, v, None, None).map(Reply::Single),
+        }
+    }
+
+    /// Handle a binary frame laid out as described for
+    /// `MessageRequestBinary`
+    fn call_binary(&mut self, conn: &Connection, b: Vec<u8>) -> Option<Reply> {
+        match MessageRequestBinary::decode(b) {
+            Some(r) => self.call(conn, r.header, Some(r.payload), None).map(Reply::Single),
+            None => {
+                debug!("[socket] Malformed binary frame");
+                Some(Reply::Single(MessageResponse::error(Value::Null, ResponseError::ParseError)))
             }
         }
-        None
+    }
+
+    /// Handle a single request object, along with the payload of a
+    /// binary frame if it came in one, and its place in the batch if it
+    /// came in one. Returns `None` for notifications, and for deferred
+    /// calls.
+    fn call(&mut self,
+            conn: &Connection,
+            v: Value,
+            payload: Option<Vec<u8>>,
+            slot: Option<Arc<Slot>>)
+            -> Option<MessageResponse> {
+        // Read from the object itself, as `MessageRequest` cannot tell a
+        // notification, with no `id`, from a call with an `id` of `null`
+        let id = v.as_object().and_then(|o| o.get("id")).cloned();
+        let req = match serde_json::from_value::<MessageRequest>(v) {
+            Ok(ref req) if req.jsonrpc != JSONRPC_VERSION => None,
+            Ok(req) => Some(req),
+            Err(_) => None,
+        };
+        let req = match req {
+            Some(req) => req,
+            None => {
+                let id = id.unwrap_or(Value::Null);
+                return Some(MessageResponse::error(id, ResponseError::InvalidRequest));
+            }
+        };
+
+        let params = req.params.unwrap_or(Value::Null);
+        let res = match self.handlers.get_mut(&req.method[..]) {
+            Some(ref mut h) if h.is_deferred() && payload.is_none() => {
+                let reply = Responder { slot: slot, ..Responder::new(conn, id.clone()) };
+                match h.execute_deferred(conn, params, reply) {
+                    Ok(()) => return None,
+                    Err(e) => Err(e),
+                }
+            }
+            Some(h) => {
+                match payload {
+                    Some(p) => h.execute_binary(conn, params, p),
+                    None => h.execute(conn, params),
+                }
+            }
+            None => Err(ResponseError::MethodNotFound),
+        };
+        id.map(|id| {
+            match res {
+                Ok(r) => MessageResponse::success(id, r),
+                Err(e) => MessageResponse::error(id, e),
+            }
+        })
     }
 }
 
@@ -67,28 +502,67 @@ impl fmt::Display for SocketType {
 }
 
 /// `WebSocket` handler that handles each connection
-struct SocketHandler<'a, H: APIHandlerCommand + 'static> {
-    id: u64,
-    sender: Sender,
-    handler: Arc<Mutex<APIHandler<'a, H>>>,
+struct SocketHandler<'a> {
+    conn: Connection,
+    handler: APIHandler<'a>,
+    on_close: Vec<CloseHook>,
     conn_type: SocketType,
+    tls: Option<TlsContext>,
+    auth: Option<TokenVerifier>,
 }
 
-impl<'a, H: APIHandlerCommand + 'static> Handler for SocketHandler<'a, H> {
+impl<'a> Handler for SocketHandler<'a> {
+    /// Accept the first subprotocol offered that names an `Encoding`.
+    /// Connections offering none of them use JSON.
+    ///
+    /// With token authentication, the handshake is answered with
+    /// `401 Unauthorized` if the token is not accepted.
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = try!(Response::from_request(req));
+        if let Some(ref auth) = self.auth {
+            match auth.authenticate(req) {
+                Ok(Some(identity)) => {
+                    debug!("[socket] Connection {} authenticated as {}",
+                           self.conn.id,
+                           identity.name());
+                    self.conn.authenticate(identity);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    info!("[socket] Refusing connection {}: {}", self.conn.id, e);
+                    let challenge = match e {
+                        TokenError::Missing => "Bearer".to_string(),
+                        _ => format!("Bearer error=\"invalid_token\", error_description=\"{}\"", e),
+                    };
+                    res.set_status(401);
+                    res.set_reason("Unauthorized");
+                    res.headers_mut()
+                        .push(("WWW-Authenticate".to_string(), challenge.into_bytes()));
+                    return Ok(res);
+                }
+            }
+        }
+        let encoding = try!(req.protocols()).into_iter().filter_map(Encoding::from_protocol).next();
+        if let Some(e) = encoding {
+            res.set_protocol(e.protocol());
+            self.conn.encoding = e;
+        }
+        Ok(res)
+    }
+
     fn on_open(&mut self, _: Handshake) -> Result<()> {
-        debug!("[socket] Opening connection. sender: {}. Type: {}",
-               self.id,
-               self.conn_type);
+        debug!("[socket] Opening connection. sender: {}. Type: {}. Encoding: {:?}",
+               self.conn.id,
+               self.conn_type,
+               self.conn.encoding);
         // TODO: Perform tasks immediately after connection open.
         Ok(())
     }
 
     fn on_message(&mut self, m: Message) -> Result<()> {
-        if let Ok(mut l) = self.handler.lock() {
-            if let Some(res) = l.handle(self.sender.clone(), m) {
-                if let Ok(t) = serde_json::to_string(&res) {
-                    let _ = self.sender.send(Message::Text(t));
-                }
+        if let Some(res) = self.handler.handle(&self.conn, m) {
+            if let Some(f) = res.encode(self.conn.encoding) {
+                let _ = self.conn.sender.send(f);
             }
         }
         Ok(())
@@ -96,69 +570,287 @@ impl<'a, H: APIHandlerCommand + 'static> Handler for SocketHandler<'a, H> {
 
     fn on_close(&mut self, _: CloseCode, _: &str) {
         debug!("[socket] Removing sender: {}. Type: {}",
-               self.id,
+               self.conn.id,
                self.conn_type);
-        // TODO: Do connection cleanup here
+        for hook in &self.on_close {
+            hook(self.conn.id);
+        }
+    }
+
+    #[cfg(feature = "ssl")]
+    fn build_ssl(&mut self) -> Result<Ssl> {
+        match self.tls {
+            Some(ref t) => t.ssl(),
+            None => Err(::ws::Error::new(::ws::ErrorKind::Internal, "TLS is not configured")),
+        }
     }
 }
 
 /// Factory for generating `SocketHandler`
-struct SocketFactory<'a, H: APIHandlerCommand + 'static> {
-    handler: Arc<Mutex<APIHandler<'a, H>>>,
+struct SocketFactory<'a> {
+    /// Handler table copied into every new connection
+    handler: APIHandler<'a>,
+    on_close: Vec<CloseHook>,
     counter: u64,
+    tls: Option<TlsContext>,
+    auth: Option<TokenVerifier>,
 }
 
-impl<'a, H: APIHandlerCommand + 'static> SocketFactory<'a, H> {
-    fn new_handler(&mut self, s: Sender, t: SocketType) -> SocketHandler<'a, H> {
+impl<'a> SocketFactory<'a> {
+    fn new_handler(&mut self, s: Sender, t: SocketType) -> SocketHandler<'a> {
         self.counter += 1;
         SocketHandler {
-            id: self.counter,
-            sender: s,
+            conn: Connection::new(self.counter, s),
             handler: self.handler.clone(),
+            on_close: self.on_close.clone(),
             conn_type: t,
+            tls: self.tls.clone(),
+            auth: self.auth.clone(),
         }
     }
 }
 
-impl<'a, H: APIHandlerCommand + 'static> Factory for SocketFactory<'a, H> {
-    type Handler = SocketHandler<'a, H>;
+impl<'a> Factory for SocketFactory<'a> {
+    type Handler = SocketHandler<'a>;
 
-    fn connection_made(&mut self, s: Sender) -> SocketHandler<'a, H> {
+    fn connection_made(&mut self, s: Sender) -> SocketHandler<'a> {
         self.new_handler(s, SocketType::Client)
     }
 
-    fn server_connected(&mut self, s: Sender) -> SocketHandler<'a, H> {
+    fn server_connected(&mut self, s: Sender) -> SocketHandler<'a> {
         self.new_handler(s, SocketType::Server)
     }
 }
 
 /// JSON over `WebSockets` implementation with multi-client support
-pub struct WebSocket<'a, H: APIHandlerCommand + 'static> {
-    sock: Option<WS<SocketFactory<'a, H>>>,
-    handler: APIHandler<'a, H>,
+pub struct WebSocket<'a> {
+    sock: Option<WS<SocketFactory<'a>>>,
+    handler: APIHandler<'a>,
+    on_close: Vec<CloseHook>,
+    tls: Option<TlsContext>,
+    auth: Option<TokenVerifier>,
 }
 
-impl<'a, H: APIHandlerCommand + 'static> WebSocket<'a, H> {
+impl<'a> WebSocket<'a> {
     pub fn new() -> Self {
         // Do more stuff here
         WebSocket {
             sock: None,
             handler: APIHandler::new(),
+            on_close: Vec::new(),
+            tls: None,
+            auth: None,
         }
     }
 
-    pub fn add_method(&mut self, name: &'a str, command: H) {
-        self.handler.add_handler(name, command);
+    pub fn add_method<H>(&mut self, name: &'a str, command: H)
+        where H: APIHandlerCommand + Clone + 'static
+    {
+        self.handler.add_handler(name, Box::new(command));
+    }
+
+    /// Register a callback to run with the connection id whenever a
+    /// connection closes
+    pub fn on_close<F>(&mut self, hook: F)
+        where F: Fn(u64) + Send + Sync + 'static
+    {
+        self.on_close.push(Arc::new(hook));
+    }
+
+    /// Accept `wss://` connections, encrypted with the certificates in
+    /// `tls`, instead of plain ones
+    pub fn set_tls(&mut self, tls: TlsContext) {
+        self.tls = Some(tls);
+    }
+
+    /// Authenticate connections with the bearer tokens they present,
+    /// refusing those whose token `auth` does not accept
+    pub fn set_auth(&mut self, auth: TokenVerifier) {
+        self.auth = Some(auth);
     }
 
     pub fn listen(mut self, addr: &str) -> Result<()> {
+        let settings = Settings {
+            encrypt_server: self.tls.is_some(),
+            queue_size: QUEUE_SIZE,
+            ..Settings::default()
+        };
         let factory = SocketFactory {
-            handler: Arc::new(Mutex::new(self.handler)),
+            handler: self.handler,
+            on_close: self.on_close,
             counter: 0,
+            tls: self.tls,
+            auth: self.auth,
         };
-        let s = WS::new(factory)?;
+        let s = try!(Builder::new().with_settings(settings).build(factory));
 
         self.sock = Some(s.listen(addr).unwrap());
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use testing::{RECV_TIMEOUT_MS, connect, is_silent};
+
+    #[derive(Clone)]
+    struct Echo;
+
+    /// Defers calls without params, handing their `Responder` over to the
+    /// test, and refuses the others
+    #[derive(Clone)]
+    struct Later(mpsc::Sender<Responder>);
+
+    impl APIHandlerCommand for Later {
+        fn execute(&mut self,
+                   _: &Connection,
+                   _: Value)
+                   -> ::std::result::Result<Value, ResponseError> {
+            Err(ResponseError::InvalidRequest)
+        }
+
+        fn is_deferred(&self) -> bool {
+            true
+        }
+
+        fn execute_deferred(&mut self,
+                            _: &Connection,
+                            params: Value,
+                            reply: Responder)
+                            -> ::std::result::Result<(), ResponseError> {
+            if params != Value::Null {
+                return Err(ResponseError::InvalidPayload);
+            }
+            let _ = self.0.send(reply);
+            Ok(())
+        }
+    }
+
+    /// Ids of the responses in the next frame on `frames`, a batch
+    fn next_batch_ids(frames: &mpsc::Receiver<Message>) -> Vec<Value> {
+        let m = frames.recv_timeout(Duration::from_millis(RECV_TIMEOUT_MS)).unwrap();
+        let batch: Value = serde_json::from_str(m.as_text().unwrap()).unwrap();
+        batch.as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r.as_object().and_then(|o| o.get("id")).cloned().unwrap())
+            .collect()
+    }
+
+    impl APIHandlerCommand for Echo {
+        fn execute(&mut self,
+                   _: &Connection,
+                   params: Value)
+                   -> ::std::result::Result<Value, ResponseError> {
+            Ok(params)
+        }
+    }
+
+    fn handle(request: &str) -> Option<Reply> {
+        let sender = WS::new(|_| |_| Ok(())).unwrap().broadcaster();
+        let conn = Connection::new(1, sender);
+        let mut handler = APIHandler::new();
+        handler.add_handler("echo", Box::new(Echo));
+        handler.handle(&conn, Message::text(request))
+    }
+
+    fn single(reply: Option<Reply>) -> MessageResponse {
+        match reply {
+            Some(Reply::Single(r)) => r,
+            _ => panic!("expected a single response"),
+        }
+    }
+
+    #[test]
+    fn answers_calls_with_their_id() {
+        let r = single(handle(r#"{"jsonrpc": "2.0", "id": "a", "method": "echo", "params": 1}"#));
+        assert_eq!(r.id, Value::String("a".to_string()));
+        assert_eq!(r.result, Some(serde_json::from_str("1").unwrap()));
+    }
+
+    #[test]
+    fn answers_calls_with_a_null_id() {
+        let r = single(handle(r#"{"jsonrpc": "2.0", "id": null, "method": "echo", "params": 1}"#));
+        assert_eq!(r.id, Value::Null);
+        assert_eq!(r.result, Some(serde_json::from_str("1").unwrap()));
+
+        let r = single(handle(r#"{"jsonrpc": "2.0", "id": null, "method": "nope"}"#));
+        assert_eq!(r.id, Value::Null);
+        assert!(r.error.is_some());
+    }
+
+    #[test]
+    fn does_not_answer_notifications() {
+        assert!(handle(r#"{"jsonrpc": "2.0", "method": "echo", "params": 1}"#).is_none());
+        assert!(handle(r#"{"jsonrpc": "2.0", "method": "nope"}"#).is_none());
+    }
+
+    #[test]
+    fn answers_only_the_calls_of_a_batch() {
+        let batch = r#"[{"jsonrpc": "2.0", "method": "echo"},
+                        {"jsonrpc": "2.0", "id": null, "method": "echo"},
+                        {"jsonrpc": "2.0", "id": 2, "method": "echo"}]"#;
+        match handle(batch) {
+            Some(Reply::Batch(r)) => {
+                let ids: Vec<Value> = r.into_iter().map(|r| r.id).collect();
+                assert_eq!(ids, vec![Value::Null, serde_json::from_str("2").unwrap()]);
+            }
+            _ => panic!("expected a batch response"),
+        }
+    }
+
+    #[test]
+    fn answers_batches_with_deferred_calls_in_one_array() {
+        let (sender, frames) = connect();
+        let conn = Connection::new(1, sender);
+        let (deferred, responders) = mpsc::channel();
+        let mut handler = APIHandler::new();
+        handler.add_handler("echo", Box::new(Echo));
+        handler.add_handler("later", Box::new(Later(deferred)));
+
+        let batch = r#"[{"jsonrpc": "2.0", "id": 1, "method": "later"},
+                        {"jsonrpc": "2.0", "id": 2, "method": "echo"},
+                        {"jsonrpc": "2.0", "method": "later"},
+                        {"jsonrpc": "2.0", "id": 3, "method": "later", "params": 1}]"#;
+        assert!(handler.handle(&conn, Message::text(batch)).is_none());
+        let first = responders.recv().unwrap();
+        let notification = responders.recv().unwrap();
+
+        first.respond(Ok(Value::Null));
+        assert!(is_silent(&frames));
+        // Dropped without an answer, as a notification gets none anyway
+        drop(notification);
+        let ids: Vec<Value> = serde_json::from_str("[1, 2, 3]").unwrap();
+        assert_eq!(next_batch_ids(&frames), ids);
+
+        // Answering again sends nothing more
+        first.respond(Ok(Value::Null));
+        assert!(is_silent(&frames));
+    }
+
+    #[test]
+    fn answers_batches_whose_deferred_calls_fail_right_away() {
+        let (deferred, _responders) = mpsc::channel();
+        let sender = WS::new(|_| |_| Ok(())).unwrap().broadcaster();
+        let conn = Connection::new(1, sender);
+        let mut handler = APIHandler::new();
+        handler.add_handler("later", Box::new(Later(deferred)));
+
+        let batch = r#"[{"jsonrpc": "2.0", "id": 1, "method": "later", "params": 1},
+                        {"jsonrpc": "2.0", "method": "later", "params": 1}]"#;
+        match handler.handle(&conn, Message::text(batch)) {
+            Some(Reply::Batch(r)) => {
+                assert_eq!(r.len(), 1);
+                assert!(r[0].error.is_some());
+            }
+            _ => panic!("expected a batch response"),
+        }
+    }
+}
@@ -1,106 +1,2521 @@
 //! Handles routing between topics
+//!
+//! A `Registry` holds topics and the subscriptions to them, exact or
+//! wildcard, and delivers each published message to the subscribers it
+//! reaches. A `Router` splits topics over several registries, each
+//! running on its own thread.
 
-use ws::{Sender, Message};
-use std::collections::HashMap;
+use ws::{self, Sender, Message};
+use serde_json;
+use serde_json::Value;
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, channel, RecvTimeoutError, SendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use datastore::DataStore;
+use filter::Filter;
+use network::encoding::Encoding;
+use network::websocket::Responder;
+use schema::config_schema::RouterConfig;
+use schema::message_schema::{BINARY_MARKER, MessageNotification, MessageRequestBinary,
+                             ResponseError, encode_binary};
+use schema::topic_schema::{TopicDeadLetter, TopicInfo, TopicKey, TopicMessage, TopicMessageHeader,
+                           TopicMeta, TopicReply};
+use util::now_millis;
+
+/// Separator between levels of a topic id, as in `plant/line1/temp`
+pub const LEVEL_SEPARATOR: char = '/';
+
+/// Wildcard matching exactly one level, as in `plant/+/temp`
+pub const SINGLE_LEVEL_WILDCARD: &'static str = "+";
+
+/// Wildcard matching all remaining levels, including none, as in
+/// `plant/#`
+pub const MULTI_LEVEL_WILDCARD: &'static str = "#";
+
+/// Prefix of the topic ids of reply-to inboxes. An inbox waits for a
+/// single reply: the first message published to it with the correlation
+/// id of its request answers the request, without reaching any
+/// subscriber, and closes it. Other messages are dropped.
+pub const INBOX_PREFIX: &'static str = "_inbox/";
+
+/// Check if a topic id is a reply-to inbox
+pub fn is_inbox(topic_id: &str) -> bool {
+    topic_id.starts_with(INBOX_PREFIX)
+}
+
+/// Check if a topic id or subscription pattern contains wildcards
+pub fn is_pattern(topic_id: &str) -> bool {
+    topic_id.split(LEVEL_SEPARATOR)
+        .any(|l| l == SINGLE_LEVEL_WILDCARD || l == MULTI_LEVEL_WILDCARD)
+}
+
+/// Check if `topic_id` names a single topic, with no wildcard character
+/// in any of its levels
+pub fn is_topic_id(topic_id: &str) -> bool {
+    !topic_id.contains(|c| c == '+' || c == '#')
+}
+
+/// Check if a subscription pattern is well formed. Wildcards must take
+/// up a whole level and `#` may only appear as the last level.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let levels: Vec<&str> = pattern.split(LEVEL_SEPARATOR).collect();
+    let last = levels.len() - 1;
+    levels.iter().enumerate().all(|(i, l)| {
+        if *l == MULTI_LEVEL_WILDCARD {
+            i == last
+        } else {
+            *l == SINGLE_LEVEL_WILDCARD || !(l.contains('+') || l.contains('#'))
+        }
+    })
+}
+
+/// Check if `topic_id` is matched by the subscription `pattern`
+pub fn matches_pattern(pattern: &str, topic_id: &str) -> bool {
+    let mut levels = topic_id.split(LEVEL_SEPARATOR);
+    for p in pattern.split(LEVEL_SEPARATOR) {
+        if p == MULTI_LEVEL_WILDCARD {
+            return true;
+        }
+        match levels.next() {
+            Some(l) if p == SINGLE_LEVEL_WILDCARD || p == l => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Check if every topic id matched by the topic id or pattern `pattern`
+/// is also matched by the pattern `other`
+pub fn covers_pattern(other: &str, pattern: &str) -> bool {
+    let mut levels = pattern.split(LEVEL_SEPARATOR);
+    for o in other.split(LEVEL_SEPARATOR) {
+        if o == MULTI_LEVEL_WILDCARD {
+            return true;
+        }
+        match levels.next() {
+            Some(l) if l == MULTI_LEVEL_WILDCARD => return false,
+            Some(l) if o == SINGLE_LEVEL_WILDCARD || o == l => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Id of a client connection, as assigned by the network layer
+pub type ConnectionId = u64;
+
+/// History settings requested for a new topic. Unset values fall back
+/// to the router defaults.
+#[derive(Clone, Debug, Default)]
+pub struct TopicOptions {
+    pub history_size: Option<usize>,
+    pub history_age_ms: Option<u64>,
+    pub durable: Option<bool>,
+}
+
+/// Options for a new subscription
+#[derive(Clone, Debug, Default)]
+pub struct SubscribeOptions {
+    /// Replay kept messages starting at this sequence number
+    pub from_seq: Option<u64>,
+    /// Replay at most this many of the latest kept messages
+    pub last_n: Option<usize>,
+}
+
+impl SubscribeOptions {
+    fn wants_replay(&self) -> bool {
+        self.from_seq.is_some() || self.last_n.is_some()
+    }
+}
+
+/// Options for a published message
+#[derive(Clone, Debug, Default)]
+pub struct PublishOptions {
+    /// Make the message the topic's retained value, which each new
+    /// subscriber gets as it subscribes. An empty one clears it.
+    pub retain: bool,
+    /// Inbox to publish the reply to, for requests
+    pub reply_to: Option<String>,
+    /// Id of the request, or of the request the message replies to
+    pub correlation_id: Option<String>,
+    /// Milliseconds after which the message expires. It is then dropped
+    /// from history, from the retained value and from the deliveries
+    /// waiting for an ack, so that subscribers coming back later do not
+    /// get it.
+    pub ttl_ms: Option<u64>,
+    /// Subscriber id of the publishing connection, whose subscription
+    /// under it does not get the message
+    pub sender: Option<String>,
+}
+
+#[derive(Clone)]
 pub enum RouterCommand {
-    CreateTopic(String),
-    Subscribe(String, String, Sender),
-    Send(String, String, Message),
+    CreateTopic(String, TopicOptions),
+    /// Subscribe to a topic or pattern, replying whether the
+    /// subscription was made
+    Subscribe(String,
+              String,
+              Subscriber,
+              SubscribeOptions,
+              mpsc::Sender<Result<(), ResponseError>>),
+    Send(String, Option<String>, Message, PublishOptions),
     Broadcast(String, Message),
-    Unsubscribe(String, String),
+    /// Drop a subscription, by topic id, subscriber id and the
+    /// connection holding it
+    Unsubscribe(String, String, ConnectionId),
+    /// Drop every subscription held by a closed connection
+    Disconnect(ConnectionId),
+    /// Delete a topic, replying whether it existed
+    DeleteTopic(String, mpsc::Sender<bool>),
+    /// List up to a number of the visible topic ids starting with a
+    /// prefix, after an optional topic id, replying with them in order
+    ListTopics(String, Option<String>, usize, Visible, mpsc::Sender<Vec<String>>),
+    /// Reply with the state of a topic, if it exists
+    DescribeTopic(String, mpsc::Sender<Option<TopicInfo>>),
+    /// Ack a message delivered at least once, by topic id, subscriber id
+    /// and delivery id
+    Ack(String, String, u64),
+    /// Open a reply-to inbox for a request, by inbox topic id,
+    /// correlation id, responder of the call and timeout in milliseconds
+    AwaitReply(String, String, Responder, Option<u64>),
+}
+
+/// Check if a topic is to be listed, by topic id
+pub type Visible = Arc<Fn(&str) -> bool + Send + Sync>;
+
+/// A message as delivered to subscribers, built as JSON once per
+/// publish and shared between history and the retained value
+pub type Frame = Arc<Message>;
+
+/// A single subscription on a topic or pattern
+#[derive(Clone)]
+pub struct Subscriber {
+    /// Connection the subscription belongs to
+    pub connection: ConnectionId,
+    /// Handle used to deliver messages to the connection
+    pub sender: Sender,
+    /// Encoding frames are delivered in
+    pub encoding: Encoding,
+    /// Group sharing the messages of the subscription. Each message goes
+    /// to one member of each group, the members taking turns.
+    pub group: Option<String>,
+    /// Deliver at least once. Each delivery carries a delivery id and is
+    /// made again until acked, or published on the dead-letter topic of
+    /// its topic after the last attempt.
+    pub acks: bool,
+    /// Only deliver messages whose body matches
+    pub filter: Option<Arc<Filter>>,
+}
+
+impl Subscriber {
+    pub fn new(connection: ConnectionId, sender: Sender, encoding: Encoding) -> Self {
+        Subscriber {
+            connection: connection,
+            sender: sender,
+            encoding: encoding,
+            group: None,
+            acks: false,
+            filter: None,
+        }
+    }
+
+    /// Join the subscription to `group`, if set
+    pub fn in_group(mut self, group: Option<String>) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Deliver messages at least once if `acks` is set
+    pub fn with_acks(mut self, acks: bool) -> Self {
+        self.acks = acks;
+        self
+    }
+
+    /// Only deliver messages matching `filter`, if set
+    pub fn with_filter(mut self, filter: Option<Filter>) -> Self {
+        self.filter = filter.map(Arc::new);
+        self
+    }
+
+    /// Check if the message whose body is `body` is to be delivered
+    fn accepts(&self, body: &mut Body) -> bool {
+        match self.filter {
+            Some(ref f) => body.get().map_or(false, |b| f.matches(b)),
+            None => true,
+        }
+    }
+
+    /// Queue a copy of `frame`, already in the subscriber's encoding, on
+    /// its connection
+    ///
+    /// TODO: Hand the shared frame itself to the connection. `ws` 0.5
+    /// only queues messages that own their payload, so until it can
+    /// send a shared buffer a payload is still copied once per
+    /// subscriber.
+    pub fn deliver(&self, frame: &Frame) -> ws::Result<()> {
+        self.sender.send((**frame).clone())
+    }
+}
+
+/// The JSON body of the message a frame was built from. `None` for
+/// binary messages and bodies that are not JSON.
+fn message_body(frame: &Message) -> Option<Value> {
+    let n = match *frame {
+        Message::Text(ref t) => serde_json::from_str::<Value>(t).ok(),
+        Message::Binary(_) => None,
+    };
+    let n = match n {
+        Some(n) => n,
+        None => return None,
+    };
+    let message = n.as_object()
+        .and_then(|o| o.get("params"))
+        .and_then(|p| p.as_object())
+        .and_then(|p| p.get("message"))
+        .and_then(|m| m.as_str());
+    message.and_then(|m| serde_json::from_str::<Value>(m).ok())
+}
+
+/// Body of a published message, parsed the first time a filter needs it
+struct Body<'a> {
+    frame: &'a Message,
+    parsed: Option<Option<Value>>,
+}
+
+impl<'a> Body<'a> {
+    fn new(frame: &'a Message) -> Self {
+        Body {
+            frame: frame,
+            parsed: None,
+        }
+    }
+
+    fn get(&mut self) -> Option<&Value> {
+        if self.parsed.is_none() {
+            self.parsed = Some(message_body(self.frame));
+        }
+        self.parsed.as_ref().and_then(|b| b.as_ref())
+    }
+}
+
+/// `frame`, built as JSON, in `encoding`. Binary messages keep the
+/// layout of `MessageRequestBinary` whatever the encoding, preceded by
+/// `BINARY_MARKER` on MessagePack and CBOR connections.
+fn transcode(frame: &Frame, encoding: Encoding) -> Frame {
+    if encoding == Encoding::Json {
+        return frame.clone();
+    }
+    let encoded = match **frame {
+        Message::Text(ref t) => {
+            serde_json::from_str::<Value>(t).ok().and_then(|v| encoding.encode(&v))
+        }
+        Message::Binary(ref b) => {
+            let mut marked = Vec::with_capacity(1 + b.len());
+            marked.push(BINARY_MARKER);
+            marked.extend_from_slice(b);
+            Some(Message::Binary(marked))
+        }
+    };
+    encoded.map_or_else(|| frame.clone(), Arc::new)
+}
+
+/// Add the delivery id and attempt number of an at-least-once delivery
+/// to the params of a notification
+fn stamp_delivery(n: &mut Value, delivery_id: u64, attempt: u32) {
+    let params = n.as_object_mut()
+        .and_then(|o| o.get_mut("params"))
+        .and_then(|p| p.as_object_mut());
+    if let Some(p) = params {
+        p.insert("delivery_id".to_string(), serde_json::to_value(&delivery_id));
+        p.insert("attempt".to_string(), serde_json::to_value(&attempt));
+    }
+}
+
+/// `frame`, built as JSON, stamped for an at-least-once delivery
+fn with_delivery(frame: &Frame, delivery_id: u64, attempt: u32) -> Frame {
+    let stamped = match **frame {
+        Message::Text(ref t) => {
+            serde_json::from_str::<Value>(t).ok().and_then(|mut n| {
+                stamp_delivery(&mut n, delivery_id, attempt);
+                serde_json::to_string(&n).ok().map(Message::Text)
+            })
+        }
+        Message::Binary(ref b) => {
+            MessageRequestBinary::decode(b.clone()).and_then(|mut r| {
+                stamp_delivery(&mut r.header, delivery_id, attempt);
+                encode_binary(&r.header, &r.payload).map(Message::Binary)
+            })
+        }
+    };
+    stamped.map_or_else(|| frame.clone(), Arc::new)
+}
+
+/// A published frame, transcoded on demand and at most once for each
+/// encoding
+struct Encoded {
+    frames: Vec<(Encoding, Frame)>,
+}
+
+impl Encoded {
+    fn new(frame: Frame) -> Self {
+        Encoded { frames: vec![(Encoding::Json, frame)] }
+    }
+
+    fn get(&mut self, encoding: Encoding) -> Frame {
+        if let Some(&(_, ref f)) = self.frames.iter().find(|&&(e, _)| e == encoding) {
+            return f.clone();
+        }
+        let f = transcode(&self.frames[0].1, encoding);
+        self.frames.push((encoding, f.clone()));
+        f
+    }
+}
+
+/// Bounds on the history kept by a topic
+#[derive(Clone, Debug)]
+pub struct HistoryLimits {
+    /// Maximum number of messages kept
+    pub size: usize,
+    /// Maximum age of kept messages
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryLimits {
+    fn new(size: usize, age_ms: Option<u64>) -> Self {
+        HistoryLimits {
+            size: size,
+            max_age: age_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        let conf = RouterConfig::default();
+        HistoryLimits::new(conf.history_size, conf.history_age_ms)
+    }
+}
+
+/// Length of the windows the message rate of a topic is measured over
+const RATE_WINDOW_SECS: u64 = 10;
+
+/// Counts messages over fixed windows, to report a recent rate
+struct RateMeter {
+    started: Instant,
+    count: u64,
+    /// Rate over the last complete window
+    last: Option<f64>,
+}
+
+impl RateMeter {
+    fn new() -> Self {
+        RateMeter {
+            started: Instant::now(),
+            count: 0,
+            last: None,
+        }
+    }
+
+    /// Start a new window once the current one is over
+    fn roll(&mut self) {
+        let window = Duration::from_secs(RATE_WINDOW_SECS);
+        let elapsed = self.started.elapsed();
+        if elapsed < window {
+            return;
+        }
+        // Nothing was counted in the windows after the current one
+        self.last = Some(if elapsed < window * 2 {
+            self.count as f64 / RATE_WINDOW_SECS as f64
+        } else {
+            0.0
+        });
+        self.started = Instant::now();
+        self.count = 0;
+    }
+
+    fn tick(&mut self) {
+        self.roll();
+        self.count += 1;
+    }
+
+    /// Messages per second. Until a window is complete, the messages so
+    /// far are spread over a whole window.
+    fn rate(&mut self) -> f64 {
+        self.roll();
+        let count = self.count;
+        self.last.unwrap_or_else(|| count as f64 / RATE_WINDOW_SECS as f64)
+    }
+}
+
+/// A message kept in topic history, or as the retained value
+struct Record {
+    seq: u64,
+    published: Instant,
+    frame: Frame,
+    /// Expiry, in milliseconds since the Unix epoch, of messages
+    /// published with a ttl
+    expires_ms: Option<u64>,
+}
+
+impl Record {
+    fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_ms.map_or(false, |e| e <= now_ms)
+    }
+
+    /// Time left until the record expires
+    fn ttl(&self) -> Option<Duration> {
+        self.expires_ms.map(|e| Duration::from_millis(e.saturating_sub(now_millis())))
+    }
+}
+
+/// The shorter of two optional durations
+fn shortest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+const META_PREFIX: &'static str = "topicmeta:";
+
+fn meta_key(topic_id: &str) -> String {
+    format!("{}{}", META_PREFIX, topic_id)
+}
+
+fn message_prefix(topic_id: &str) -> String {
+    format!("topic:{}:", topic_id)
+}
+
+fn message_key(topic_id: &str, seq: u64) -> String {
+    format!("{}{:020}", message_prefix(topic_id), seq)
+}
+
+fn retained_key(topic_id: &str) -> String {
+    format!("topicretained:{}", topic_id)
+}
+
+/// Little endian bytes of `n`
+fn u64_to_le(n: u64) -> Vec<u8> {
+    (0..8).map(|i| (n >> (8 * i)) as u8).collect()
+}
+
+fn u64_from_le(b: &[u8]) -> u64 {
+    b[..8].iter().enumerate().fold(0, |s, (i, b)| s | (*b as u64) << (8 * i))
+}
+
+/// Encode a frame for the datastore, tagging it as text or binary. A
+/// frame that expires is preceded by an `e` tag and its expiry.
+fn encode_frame(m: &Message, expires_ms: Option<u64>) -> Vec<u8> {
+    let (tag, data) = match *m {
+        Message::Text(ref t) => (b't', t.as_bytes()),
+        Message::Binary(ref b) => (b'b', &b[..]),
+    };
+    let mut out = Vec::with_capacity(data.len() + 10);
+    if let Some(e) = expires_ms {
+        out.push(b'e');
+        out.extend(u64_to_le(e));
+    }
+    out.push(tag);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Decode a frame written by `encode_frame`, along with its expiry
+fn decode_frame(mut b: Vec<u8>) -> Option<(Message, Option<u64>)> {
+    if b.is_empty() {
+        return None;
+    }
+    let tag = b.remove(0);
+    match tag {
+        b't' => String::from_utf8(b).ok().map(|t| (Message::Text(t), None)),
+        b'b' => Some((Message::Binary(b), None)),
+        b'e' if b.len() > 8 => {
+            let expires_ms = u64_from_le(&b);
+            decode_frame(b.split_off(8)).map(|(m, _)| (m, Some(expires_ms)))
+        }
+        _ => None,
+    }
 }
 
+/// A topic, numbering its messages with a monotonically increasing
+/// sequence number and keeping a bounded history of them for
+/// subscribers to catch up on. Durable topics also write their history
+/// to a `DataStore`.
 pub struct Topic {
     id: String,
-    subscribers: HashMap<String, Sender>,
+    subscribers: HashMap<String, Subscriber>,
+    limits: HistoryLimits,
+    history: VecDeque<Record>,
+    next_seq: u64,
+    /// Last retained message
+    retained: Option<Record>,
+    /// Earliest expiry among kept messages, if any expire
+    next_expiry: Option<u64>,
+    /// Kept messages and pending deliveries dropped for being expired
+    expired: u64,
+    /// Deliveries dropped for a full outgoing queue
+    dropped: u64,
+    store: Option<Arc<DataStore>>,
+    /// Creation time, in milliseconds since the Unix epoch
+    created_ms: u64,
+    rate: RateMeter,
 }
 
 impl Topic {
     pub fn new(id: String) -> Self {
+        Topic::with_limits(id, HistoryLimits::default())
+    }
+
+    pub fn with_limits(id: String, limits: HistoryLimits) -> Self {
         Topic {
             id: id,
             subscribers: HashMap::new(),
+            limits: limits,
+            history: VecDeque::new(),
+            next_seq: 1,
+            retained: None,
+            next_expiry: None,
+            expired: 0,
+            dropped: 0,
+            store: None,
+            created_ms: now_millis(),
+            rate: RateMeter::new(),
+        }
+    }
+
+    /// Load a durable topic and its history back from `store`
+    pub fn restore(meta: TopicMeta, store: Arc<DataStore>) -> io::Result<Self> {
+        let limits = HistoryLimits::new(meta.history_size, meta.history_age_ms);
+        let mut topic = Topic::with_limits(meta.topic_id, limits);
+        if let Some(ms) = meta.created_ms {
+            topic.created_ms = ms;
         }
+
+        let prefix = message_prefix(&topic.id);
+        for (k, v) in try!(store.scan(&prefix)) {
+            // Skip keys of other topics whose id starts with this one
+            let seq = match k[prefix.len()..].parse::<u64>() {
+                Ok(seq) if k.len() == prefix.len() + 20 => seq,
+                _ => continue,
+            };
+            if let Some((frame, expires_ms)) = decode_frame(v) {
+                // The original publish time is not kept; the store has
+                // already expired anything older than the age limit.
+                topic.keep(Record {
+                    seq: seq,
+                    published: Instant::now(),
+                    frame: Arc::new(frame),
+                    expires_ms: expires_ms,
+                });
+                topic.next_seq = seq + 1;
+            }
+        }
+        if let Some(v) = try!(store.get(&retained_key(&topic.id))) {
+            if v.len() > 8 {
+                let seq = u64_from_le(&v);
+                topic.retained = decode_frame(v[8..].to_vec()).map(|(f, expires_ms)| {
+                    Record {
+                        seq: seq,
+                        published: Instant::now(),
+                        frame: Arc::new(f),
+                        expires_ms: expires_ms,
+                    }
+                });
+                if let Some(e) = topic.retained.as_ref().and_then(|r| r.expires_ms) {
+                    topic.note_expiry(e);
+                }
+                if seq >= topic.next_seq {
+                    topic.next_seq = seq + 1;
+                }
+            }
+        }
+        topic.store = Some(store);
+        topic.prune();
+        Ok(topic)
+    }
+
+    /// Make the topic durable, writing its settings and current history
+    /// to `store`. For a topic that is already durable, only the
+    /// settings are written again.
+    pub fn persist_to(&mut self, store: Arc<DataStore>) -> io::Result<()> {
+        let meta = TopicMeta {
+            topic_id: self.id.clone(),
+            history_size: self.limits.size,
+            history_age_ms: self.limits.max_age.map(|a| {
+                a.as_secs() * 1000 + (a.subsec_nanos() / 1_000_000) as u64
+            }),
+            created_ms: Some(self.created_ms),
+        };
+        let meta = try!(serde_json::to_vec(&meta)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        try!(store.put(&meta_key(&self.id), meta, None));
+        if !self.is_durable() {
+            for r in &self.history {
+                try!(store.put(&message_key(&self.id, r.seq),
+                               encode_frame(&r.frame, r.expires_ms),
+                               shortest(self.limits.max_age, r.ttl())));
+            }
+            if let Some(ref r) = self.retained {
+                try!(Topic::persist_retained(&*store, &self.id, r));
+            }
+        }
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// Retained values are stored as the little endian sequence number
+    /// followed by the encoded frame
+    fn persist_retained(store: &DataStore, id: &str, r: &Record) -> io::Result<()> {
+        let mut v = u64_to_le(r.seq);
+        v.extend(encode_frame(&r.frame, r.expires_ms));
+        store.put(&retained_key(id), v, r.ttl())
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.store.is_some()
+    }
+
+    /// Change the history bounds of the topic
+    pub fn set_limits(&mut self, limits: HistoryLimits) {
+        self.limits = limits;
+        self.prune();
     }
 
     pub fn id(&self) -> String {
         self.id.clone()
     }
 
-    pub fn add_subscriber(&mut self, id: String, sender: Sender) {
-        self.subscribers.insert(id, sender);
+    pub fn add_subscriber(&mut self, id: String, subscriber: Subscriber) {
+        self.subscribers.insert(id, subscriber);
     }
 
-    pub fn remove_subscriber(&mut self, id: &str) {
-        self.subscribers.remove(id);
+    pub fn remove_subscriber(&mut self, id: &str) -> Option<Subscriber> {
+        self.subscribers.remove(id)
     }
 
-    pub fn get_subscriber(&self, id: &str) -> Option<&Sender> {
+    pub fn get_subscriber(&self, id: &str) -> Option<&Subscriber> {
         self.subscribers.get(id)
     }
 
-    pub fn send(&self, sender_id: &str, m: Message) {
-        for (id, sender) in &self.subscribers {
-            if id != sender_id {
-                let _ = sender.send(m.clone());
+    pub fn subscribers(&self) -> &HashMap<String, Subscriber> {
+        &self.subscribers
+    }
+
+    /// Sequence number of the most recently published message, or `0`
+    /// if nothing has been published yet
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// The retained message, if any
+    pub fn retained(&self) -> Option<&Message> {
+        self.retained.as_ref().map(|r| &*r.frame)
+    }
+
+    /// Number of kept messages and pending deliveries dropped so far
+    /// for being expired
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Count deliveries of the topic's messages dropped for being
+    /// expired
+    pub fn count_expired(&mut self, n: u64) {
+        self.expired += n;
+    }
+
+    /// Number of deliveries of the topic's messages dropped so far for
+    /// a full outgoing queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Count deliveries of the topic's messages dropped for a full
+    /// outgoing queue
+    pub fn count_dropped(&mut self, n: u64) {
+        self.dropped += n;
+    }
+
+    /// Forget the retained message
+    pub fn clear_retained(&mut self) {
+        self.retained = None;
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.delete(&retained_key(&self.id)) {
+                error!("[router] Unable to clear retained message of {}: {}", self.id, e);
             }
         }
     }
 
-    pub fn broadcast(&self, m: Message) {
-        for s in self.subscribers.values() {
-            let _ = s.send(m.clone());
+    /// Assign the next sequence number to `m`, wrap it in a
+    /// `topic.message` notification for delivery and keep it in
+    /// history, and as the retained message if `opts.retain` is set. Binary
+    /// messages stay binary, with the notification as their header.
+    /// Returns the frame to send to subscribers.
+    ///
+    /// The notification is serialized here, once per publish, however
+    /// many subscribers it goes to.
+    pub fn publish(&mut self,
+                   publisher_id: Option<&str>,
+                   m: Message,
+                   opts: &PublishOptions)
+                   -> Frame {
+        let retain = opts.retain;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.rate.tick();
+        let expires_ms = opts.ttl_ms.map(|t| now_millis().saturating_add(t));
+
+        let frame = match m {
+            Message::Text(t) => {
+                let tm = TopicMessage {
+                    topic_id: self.id.clone(),
+                    seq: seq,
+                    publisher_id: publisher_id.map(String::from),
+                    retained: retain,
+                    message: t,
+                    reply_to: opts.reply_to.clone(),
+                    correlation_id: opts.correlation_id.clone(),
+                    delivery_id: None,
+                    attempt: None,
+                };
+                let n = MessageNotification::new("topic.message", tm);
+                match serde_json::to_string(&n) {
+                    Ok(s) => Message::Text(s),
+                    Err(e) => {
+                        error!("[router] Unable to serialize message: {}", e);
+                        Message::Text(n.params.message)
+                    }
+                }
+            }
+            Message::Binary(b) => {
+                let h = TopicMessageHeader {
+                    topic_id: self.id.clone(),
+                    seq: seq,
+                    publisher_id: publisher_id.map(String::from),
+                    retained: retain,
+                    delivery_id: None,
+                    attempt: None,
+                };
+                let n = MessageNotification::new("topic.message", h);
+                match encode_binary(&n, &b) {
+                    Some(f) => Message::Binary(f),
+                    None => {
+                        error!("[router] Unable to build binary frame for {}", self.id);
+                        Message::Binary(b)
+                    }
+                }
+            }
+        };
+        let frame = Arc::new(frame);
+        let record = Record {
+            seq: seq,
+            published: Instant::now(),
+            frame: frame.clone(),
+            expires_ms: expires_ms,
+        };
+
+        if self.limits.size > 0 {
+            if let Some(ref store) = self.store {
+                if let Err(e) = store.put(&message_key(&self.id, seq),
+                                          encode_frame(&frame, expires_ms),
+                                          shortest(self.limits.max_age, record.ttl())) {
+                    error!("[router] Unable to persist message {} of {}: {}", seq, self.id, e);
+                }
+            }
+            self.keep(Record { frame: frame.clone(), ..record });
+        }
+        if retain {
+            if let Some(ref store) = self.store {
+                if let Err(e) = Topic::persist_retained(&**store, &self.id, &record) {
+                    error!("[router] Unable to persist retained message of {}: {}", self.id, e);
+                }
+            }
+            if let Some(e) = expires_ms {
+                self.note_expiry(e);
+            }
+            self.retained = Some(record);
         }
+        self.prune();
+        frame
     }
-}
 
+    /// Remove everything the topic keeps in the datastore, if durable
+    pub fn destroy(&mut self) {
+        let store = match self.store.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let mut keys = vec![meta_key(&self.id), retained_key(&self.id)];
+        keys.extend(self.history.iter().map(|r| message_key(&self.id, r.seq)));
+        for k in keys {
+            if let Err(e) = store.delete(&k) {
+                error!("[router] Unable to delete {} of {}: {}", k, self.id, e);
+            }
+        }
+    }
 
-#[derive(Default)]
-pub struct Registry {
-    topics: HashMap<String, Topic>,
-}
+    /// Current state of the topic. Wildcard subscriptions are kept
+    /// outside of topics, so their count is given by the caller.
+    pub fn info(&mut self, pattern_subscribers: usize) -> TopicInfo {
+        self.prune();
+        let retained = self.retained.as_ref().and_then(|r| {
+            let n = match *r.frame {
+                Message::Text(ref t) => serde_json::from_str::<Value>(t).ok(),
+                Message::Binary(ref b) => MessageRequestBinary::decode(b.clone()).map(|r| r.header),
+            };
+            n.and_then(|n| n.as_object().and_then(|o| o.get("params")).cloned())
+        });
+        TopicInfo {
+            topic_id: self.id.clone(),
+            subscribers: self.subscribers.len(),
+            pattern_subscribers: pattern_subscribers,
+            message_rate: self.rate.rate(),
+            last_seq: self.last_seq(),
+            retained: retained,
+            expired: self.expired,
+            dropped: self.dropped,
+            created_ms: self.created_ms,
+            durable: self.is_durable(),
+        }
+    }
 
-impl Registry {
+    /// Add a record to history
+    fn keep(&mut self, r: Record) {
+        if let Some(e) = r.expires_ms {
+            self.note_expiry(e);
+        }
+        self.history.push_back(r);
+    }
 
-    pub fn new() -> Self {
-        Registry { topics: HashMap::new() }
+    fn note_expiry(&mut self, expires_ms: u64) {
+        self.next_expiry = Some(self.next_expiry.map_or(expires_ms, |n| n.min(expires_ms)));
+    }
+
+    /// Drop expired messages from history and the retained value
+    fn drop_expired(&mut self) {
+        let now = now_millis();
+        if self.next_expiry.map_or(true, |e| e > now) {
+            return;
+        }
+
+        let expired: Vec<u64> = self.history
+            .iter()
+            .filter(|r| r.is_expired(now))
+            .map(|r| r.seq)
+            .collect();
+        if !expired.is_empty() {
+            self.history.retain(|r| !r.is_expired(now));
+            if let Some(ref store) = self.store {
+                for seq in &expired {
+                    if let Err(e) = store.delete(&message_key(&self.id, *seq)) {
+                        error!("[router] Unable to delete message {} of {}: {}", seq, self.id, e);
+                    }
+                }
+            }
+            self.expired += expired.len() as u64;
+        }
+        let retained_expired = match self.retained {
+            Some(ref r) if r.is_expired(now) => Some(r.seq),
+            _ => None,
+        };
+        if let Some(seq) = retained_expired {
+            self.clear_retained();
+            // Counted once if it was in history too
+            if !expired.contains(&seq) {
+                self.expired += 1;
+            }
+        }
+
+        self.next_expiry = self.history
+            .iter()
+            .chain(self.retained.iter())
+            .filter_map(|r| r.expires_ms)
+            .min();
     }
 
-    pub fn create_topic(&mut self, id: String) {
-        self.topics.insert(id.clone(), Topic::new(id));
+    /// Drop history beyond the configured size or age, and expired
+    /// messages
+    fn prune(&mut self) {
+        self.drop_expired();
+        while self.history.len() > self.limits.size {
+            self.drop_oldest();
+        }
+        if let Some(age) = self.limits.max_age {
+            while self.history.front().map_or(false, |r| r.published.elapsed() > age) {
+                self.drop_oldest();
+            }
+        }
     }
 
-    pub fn subscribe(&mut self, topic_id: String, subscriber_id: String, sender: Sender) {
-        let topic = self.topics.entry(topic_id.clone()).or_insert_with(|| Topic::new(topic_id));
-        topic.add_subscriber(subscriber_id, sender);
+    fn drop_oldest(&mut self) {
+        if let Some(r) = self.history.pop_front() {
+            if let Some(ref store) = self.store {
+                if let Err(e) = store.delete(&message_key(&self.id, r.seq)) {
+                    error!("[router] Unable to delete message {} of {}: {}", r.seq, self.id, e);
+                }
+            }
+        }
     }
 
-    pub fn unsubscribe(&mut self, topic_id: &str, subscriber_id: &str) {
-        if let Some(mut t) = self.topics.get_mut(topic_id) {
-            t.remove_subscriber(subscriber_id);
+    /// Sequence number, frame and expiry of the kept messages selected
+    /// by `opts`
+    fn select(&mut self, opts: &SubscribeOptions) -> Vec<(u64, Frame, Option<u64>)> {
+        if !opts.wants_replay() {
+            return Vec::new();
+        }
+        self.prune();
+        let from = opts.from_seq.unwrap_or(0);
+        let mut out: Vec<(u64, Frame, Option<u64>)> = self.history
+            .iter()
+            .filter(|r| r.seq >= from)
+            .map(|r| (r.seq, r.frame.clone(), r.expires_ms))
+            .collect();
+        if let Some(n) = opts.last_n {
+            if out.len() > n {
+                let skip = out.len() - n;
+                out.drain(..skip);
+            }
         }
+        out
     }
 
-    pub fn send(&mut self, topic_id: &str, sender_id: &str, m: Message) {
-        if let Some(t) = self.topics.get(topic_id) {
-            t.send(sender_id, m);
+    /// Messages to send to a new subscriber before live delivery: the
+    /// replayed history selected by `opts` and the retained message, in
+    /// publish order. The retained message is sent once even if it is
+    /// part of the replay. Each comes with its expiry, if it has one.
+    pub fn catch_up(&mut self, opts: &SubscribeOptions) -> Vec<(Frame, Option<u64>)> {
+        self.prune();
+        let mut out = self.select(opts);
+        if let Some(ref r) = self.retained {
+            if !out.iter().any(|&(s, _, _)| s == r.seq) {
+                let at = out.iter().position(|&(s, _, _)| s > r.seq).unwrap_or(out.len());
+                out.insert(at, (r.seq, r.frame.clone(), r.expires_ms));
+            }
         }
+        out.into_iter().map(|(_, m, e)| (m, e)).collect()
     }
+}
 
-    pub fn broadcast(&mut self, topic_id: &str, m: Message) {
-        if let Some(t) = self.topics.get(topic_id) {
-            t.broadcast(m);
+/// A node in the `SubscriptionTrie`. Children are keyed by topic
+/// level, including the `+` and `#` wildcards.
+#[derive(Default)]
+struct TrieNode {
+    children: HashMap<String, TrieNode>,
+    subscribers: HashMap<String, Subscriber>,
+}
+
+impl TrieNode {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str], subscriber_id: &str) -> Option<Subscriber> {
+        match levels.split_first() {
+            None => self.subscribers.remove(subscriber_id),
+            Some((level, rest)) => {
+                let (removed, prune) = match self.children.get_mut(*level) {
+                    Some(child) => {
+                        let removed = child.remove(rest, subscriber_id);
+                        (removed, child.is_empty())
+                    }
+                    None => (None, false),
+                };
+                if prune {
+                    self.children.remove(*level);
+                }
+                removed
+            }
         }
     }
 
-    pub fn parse_command(&mut self, c: RouterCommand) {
-        match c {
-            RouterCommand::CreateTopic(tid) => self.create_topic(tid),
-            RouterCommand::Subscribe(tid, sid, s) => self.subscribe(tid, sid, s),
-            RouterCommand::Broadcast(tid, m) => self.broadcast(&tid, m),
-            RouterCommand::Send(tid, sid, m) => self.send(&tid, &sid, m),
-            RouterCommand::Unsubscribe(tid, sid) => self.unsubscribe(&tid, &sid),
+    fn collect<'a>(&'a self, levels: &[&str], out: &mut Vec<(&'a String, &'a Subscriber)>) {
+        // `#` also matches the parent level itself, so `a/#` matches `a`
+        if let Some(all) = self.children.get(MULTI_LEVEL_WILDCARD) {
+            out.extend(all.subscribers.iter());
         }
+
+        match levels.split_first() {
+            None => out.extend(self.subscribers.iter()),
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(rest, out);
+                }
+                if let Some(child) = self.children.get(SINGLE_LEVEL_WILDCARD) {
+                    child.collect(rest, out);
+                }
+            }
+        }
+    }
+}
+
+/// Trie of wildcard subscriptions, keyed by topic level, matched against
+/// the topic id of every published message
+#[derive(Default)]
+pub struct SubscriptionTrie {
+    root: TrieNode,
+}
+
+impl SubscriptionTrie {
+    pub fn new() -> Self {
+        SubscriptionTrie { root: TrieNode::default() }
+    }
+
+    pub fn insert(&mut self, pattern: &str, subscriber_id: String, subscriber: Subscriber) {
+        let mut node = &mut self.root;
+        for level in pattern.split(LEVEL_SEPARATOR) {
+            node = node.children.entry(level.to_string()).or_insert_with(TrieNode::default);
+        }
+        node.subscribers.insert(subscriber_id, subscriber);
+    }
+
+    pub fn get(&self, pattern: &str, subscriber_id: &str) -> Option<&Subscriber> {
+        let mut node = &self.root;
+        for level in pattern.split(LEVEL_SEPARATOR) {
+            node = match node.children.get(level) {
+                Some(n) => n,
+                None => return None,
+            };
+        }
+        node.subscribers.get(subscriber_id)
+    }
+
+    pub fn remove(&mut self, pattern: &str, subscriber_id: &str) -> Option<Subscriber> {
+        let levels: Vec<&str> = pattern.split(LEVEL_SEPARATOR).collect();
+        self.root.remove(&levels, subscriber_id)
+    }
+
+    /// Find all subscribers whose pattern matches `topic_id`. A
+    /// subscriber may appear more than once if several of its patterns
+    /// match.
+    pub fn matches(&self, topic_id: &str) -> Vec<(&String, &Subscriber)> {
+        let levels: Vec<&str> = topic_id.split(LEVEL_SEPARATOR).collect();
+        let mut out = Vec::new();
+        self.root.collect(&levels, &mut out);
+        out
+    }
+}
+
+/// Milliseconds between checks for overdue acks, and for requests past
+/// their deadline
+const REDELIVERY_CHECK_MS: u64 = 100;
+
+/// A request waiting on its reply-to inbox
+struct Inbox {
+    correlation_id: String,
+    responder: Responder,
+    /// When to give up waiting and answer with a timeout
+    deadline: Instant,
+}
+
+/// A message delivered at least once and not acked yet
+struct Pending {
+    topic_id: String,
+    subscriber_id: String,
+    subscriber: Subscriber,
+    /// The message as published, before the delivery was stamped on it
+    frame: Frame,
+    attempts: u32,
+    /// When to deliver again if no ack comes
+    due: Instant,
+    /// Expiry of the message, in milliseconds since the Unix epoch
+    expires_ms: Option<u64>,
+}
+
+/// Topics and their subscriptions, as held by a single shard
+pub struct Registry {
+    topics: HashMap<String, Topic>,
+    patterns: SubscriptionTrie,
+    /// Topic ids or patterns, and subscriber ids, held by each
+    /// connection, so that they can all be dropped at once
+    connections: HashMap<ConnectionId, HashSet<(String, String)>>,
+    /// Position of the next group member to deliver to, by topic id and
+    /// group
+    cursors: HashMap<String, HashMap<String, usize>>,
+    /// At-least-once deliveries waiting for an ack, by delivery id
+    pending: HashMap<u64, Pending>,
+    next_delivery: u64,
+    last_redelivery: Instant,
+    /// Open reply-to inboxes, by topic id
+    inboxes: HashMap<String, Inbox>,
+    last_expiry: Instant,
+    config: RouterConfig,
+    store: Option<Arc<DataStore>>,
+    /// Router of the shard the registry runs in, to reach topics that
+    /// live in other shards
+    router: Option<Router>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+
+    pub fn new() -> Self {
+        Registry::with_config(RouterConfig::default())
+    }
+
+    pub fn with_config(config: RouterConfig) -> Self {
+        Registry {
+            topics: HashMap::new(),
+            patterns: SubscriptionTrie::new(),
+            connections: HashMap::new(),
+            cursors: HashMap::new(),
+            pending: HashMap::new(),
+            next_delivery: 1,
+            last_redelivery: Instant::now(),
+            inboxes: HashMap::new(),
+            last_expiry: Instant::now(),
+            config: config,
+            store: None,
+            router: None,
+        }
+    }
+
+    /// Create a registry that keeps durable topics in `store`, loading
+    /// the ones already there.
+    pub fn with_store(config: RouterConfig, store: Arc<DataStore>) -> Self {
+        Registry::with_store_filter(config, store, |_| true)
+    }
+
+    /// Like `with_store`, but only loads the durable topics for which
+    /// `owns` returns true
+    fn with_store_filter<F>(config: RouterConfig, store: Arc<DataStore>, owns: F) -> Self
+        where F: Fn(&str) -> bool
+    {
+        let mut reg = Registry::with_config(config);
+        match store.scan(META_PREFIX) {
+            Ok(metas) => {
+                for (k, v) in metas {
+                    if !owns(&k[META_PREFIX.len()..]) {
+                        continue;
+                    }
+                    let restored = serde_json::from_slice::<TopicMeta>(&v)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                        .and_then(|meta| Topic::restore(meta, store.clone()));
+                    match restored {
+                        Ok(t) => {
+                            debug!("[router] Restored durable topic {} at seq {}",
+                                   t.id,
+                                   t.last_seq());
+                            reg.topics.insert(t.id(), t);
+                        }
+                        Err(e) => error!("[router] Unable to restore {}: {}", k, e),
+                    }
+                }
+            }
+            Err(e) => error!("[router] Unable to load durable topics: {}", e),
+        }
+        reg.store = Some(store);
+        reg
+    }
+
+    fn default_limits(&self) -> HistoryLimits {
+        HistoryLimits::new(self.config.history_size, self.config.history_age_ms)
+    }
+
+    /// Check if the topic should be written to the datastore
+    fn wants_durable(&self, topic_id: &str, requested: Option<bool>) -> bool {
+        self.store.is_some() &&
+        requested.unwrap_or_else(|| {
+            self.config.durable_topics.iter().any(|p| matches_pattern(p, topic_id))
+        })
+    }
+
+    fn make_durable(&mut self, topic_id: &str) {
+        let store = match self.store {
+            Some(ref s) => s.clone(),
+            None => return,
+        };
+        if let Some(t) = self.topics.get_mut(topic_id) {
+            if let Err(e) = t.persist_to(store) {
+                error!("[router] Unable to make {} durable: {}", topic_id, e);
+            }
+        }
+    }
+
+    fn topic_mut(&mut self, topic_id: &str) -> &mut Topic {
+        if !self.topics.contains_key(topic_id) {
+            let limits = self.default_limits();
+            self.topics.insert(topic_id.to_string(),
+                               Topic::with_limits(topic_id.to_string(), limits));
+            if self.wants_durable(topic_id, None) {
+                self.make_durable(topic_id);
+            }
+        }
+        self.topics.get_mut(topic_id).unwrap()
+    }
+
+    /// Create a topic. Creating a topic that already exists keeps its
+    /// subscribers and history, and only applies the new settings.
+    pub fn create_topic(&mut self, id: String, opts: TopicOptions) {
+        if !is_topic_id(&id) {
+            warn!("[router] Cannot create topic with wildcard id: {}", id);
+            return;
+        }
+        if is_inbox(&id) {
+            warn!("[router] Cannot create topic with inbox id: {}", id);
+            return;
+        }
+        let limits = HistoryLimits::new(opts.history_size.unwrap_or(self.config.history_size),
+                                        opts.history_age_ms.or(self.config.history_age_ms));
+        self.topic_mut(&id).set_limits(limits);
+        if self.wants_durable(&id, opts.durable) {
+            self.make_durable(&id);
+        }
+    }
+
+    /// Subscribe `subscriber` to `topic_id` as `subscriber_id`, which
+    /// replaces an earlier subscription of the same connection under
+    /// that id. Fails with `Overloaded` if the connection cannot take
+    /// the replay.
+    pub fn subscribe(&mut self,
+                     topic_id: String,
+                     subscriber_id: String,
+                     subscriber: Subscriber,
+                     opts: SubscribeOptions)
+                     -> Result<(), ResponseError> {
+        if !is_valid_pattern(&topic_id) {
+            warn!("[router] Invalid subscription pattern: {}", topic_id);
+            return Err(ResponseError::InvalidPayload);
+        }
+        if is_inbox(&topic_id) {
+            warn!("[router] Cannot subscribe to inbox: {}", topic_id);
+            return Err(ResponseError::InvalidPayload);
+        }
+
+        // Replay history and retained messages before the subscription
+        // goes live
+        let first_delivery = self.next_delivery;
+        for (tid, f, expires_ms) in self.catch_up(&topic_id, &opts) {
+            if !subscriber.accepts(&mut Body::new(&f)) {
+                continue;
+            }
+            let sent = if subscriber.acks {
+                self.deliver_tracked(&tid, &subscriber_id, subscriber.clone(), f, expires_ms)
+            } else {
+                subscriber.deliver(&transcode(&f, subscriber.encoding)).is_ok()
+            };
+            // Subscribing without the replay asked for would leave a gap
+            if !sent {
+                warn!("[router] Unable to replay {} to connection {}",
+                      topic_id,
+                      subscriber.connection);
+                self.pending.retain(|&d, _| d < first_delivery);
+                return Err(ResponseError::Overloaded);
+            }
+        }
+
+        let connection = subscriber.connection;
+        if is_pattern(&topic_id) {
+            self.patterns.insert(&topic_id, subscriber_id.clone(), subscriber);
+        } else {
+            self.topic_mut(&topic_id).add_subscriber(subscriber_id.clone(), subscriber);
+        }
+        self.connections
+            .entry(connection)
+            .or_insert_with(HashSet::new)
+            .insert((topic_id, subscriber_id));
+        Ok(())
+    }
+
+    fn subscription(&self, topic_id: &str, subscriber_id: &str) -> Option<&Subscriber> {
+        if is_pattern(topic_id) {
+            self.patterns.get(topic_id, subscriber_id)
+        } else {
+            self.topics.get(topic_id).and_then(|t| t.get_subscriber(subscriber_id))
+        }
+    }
+
+    fn remove_subscription(&mut self, topic_id: &str, subscriber_id: &str) -> Option<Subscriber> {
+        if is_pattern(topic_id) {
+            self.patterns.remove(topic_id, subscriber_id)
+        } else {
+            self.topics.get_mut(topic_id).and_then(|t| t.remove_subscriber(subscriber_id))
+        }
+    }
+
+    /// Drop the subscription `subscriber_id` to `topic_id`, if held by
+    /// `connection`
+    pub fn unsubscribe(&mut self, topic_id: &str, subscriber_id: &str, connection: ConnectionId) {
+        let owned = self.subscription(topic_id, subscriber_id)
+            .map_or(false, |s| s.connection == connection);
+        if !owned {
+            return;
+        }
+        if let Some(s) = self.remove_subscription(topic_id, subscriber_id) {
+            let now_empty = match self.connections.get_mut(&s.connection) {
+                Some(subs) => {
+                    subs.remove(&(topic_id.to_string(), subscriber_id.to_string()));
+                    subs.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.connections.remove(&s.connection);
+            }
+        }
+        self.release_pending(connection);
+    }
+
+    /// Drop every subscription held by `connection`, across all topics
+    /// and patterns.
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        let subs = match self.connections.remove(&connection) {
+            Some(s) => s,
+            None => return self.release_pending(connection),
+        };
+        debug!("[router] Dropping {} subscription(s) of connection {}",
+               subs.len(),
+               connection);
+
+        for (topic_id, subscriber_id) in subs {
+            self.remove_subscription(&topic_id, &subscriber_id);
+        }
+        self.release_pending(connection);
+    }
+
+    /// Check if `connection` holds a subscription under `subscriber_id`
+    /// covering `topic_id`, exactly or through a pattern
+    fn holds(&self, connection: ConnectionId, subscriber_id: &str, topic_id: &str) -> bool {
+        self.connections.get(&connection).map_or(false, |subs| {
+            subs.iter().any(|&(ref t, ref s)| {
+                s == subscriber_id &&
+                (t == topic_id || is_pattern(t) && matches_pattern(t, topic_id))
+            })
+        })
+    }
+
+    /// Hand the deliveries pending for subscriptions `connection` no
+    /// longer holds over to another member of their group, dropping
+    /// those made outside of a group
+    fn release_pending(&mut self, connection: ConnectionId) {
+        let orphans: Vec<u64> = self.pending
+            .iter()
+            .filter(|&(_, p)| {
+                p.subscriber.connection == connection &&
+                !self.holds(connection, &p.subscriber_id, &p.topic_id)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in orphans {
+            let p = match self.pending.remove(&id) {
+                Some(p) => p,
+                None => continue,
+            };
+            let group = match p.subscriber.group {
+                Some(ref g) => g.clone(),
+                None => {
+                    debug!("[router] Dropping delivery {} of {}", id, p.topic_id);
+                    continue;
+                }
+            };
+            match self.next_member(&p.topic_id, &group, &p.frame) {
+                Some((sid, s)) => {
+                    if s.acks {
+                        self.deliver_tracked(&p.topic_id, &sid, s, p.frame, p.expires_ms);
+                    } else if let Err(e) = s.deliver(&transcode(&p.frame, s.encoding)) {
+                        debug!("[router] Unable to send to connection {}: {}", s.connection, e);
+                    }
+                }
+                None => {
+                    debug!("[router] Dropping delivery {} of {}, group {} has no members left",
+                           id,
+                           p.topic_id,
+                           group);
+                }
+            }
+        }
+    }
+
+    /// The member of `group` subscribed to `topic_id` whose turn it is to
+    /// get `frame`, moving the turn on to the next member
+    fn next_member(&mut self,
+                   topic_id: &str,
+                   group: &str,
+                   frame: &Frame)
+                   -> Option<(String, Subscriber)> {
+        let mut body = Body::new(frame);
+        let mut members: Vec<(String, Subscriber)> = {
+            let exact = self.topics
+                .get(topic_id)
+                .into_iter()
+                .flat_map(|t| t.subscribers().iter());
+            exact.chain(self.patterns.matches(topic_id))
+                .filter(|&(_, s)| s.group.as_ref().map_or(false, |g| g == group))
+                .filter(|&(_, s)| s.accepts(&mut body))
+                .map(|(id, s)| (id.clone(), s.clone()))
+                .collect()
+        };
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        members.dedup_by_key(|m| m.0.clone());
+        if members.is_empty() {
+            return None;
+        }
+        let cursor = self.cursors
+            .entry(topic_id.to_string())
+            .or_insert_with(HashMap::new)
+            .entry(group.to_string())
+            .or_insert(0);
+        let at = *cursor % members.len();
+        *cursor = at + 1;
+        Some(members.swap_remove(at))
+    }
+
+    /// Messages a new subscriber of `topic_id` should receive before
+    /// live delivery, along with the topic they were published on and
+    /// their expiry. For patterns, every matching topic is caught up in
+    /// turn.
+    fn catch_up(&mut self,
+                topic_id: &str,
+                opts: &SubscribeOptions)
+                -> Vec<(String, Frame, Option<u64>)> {
+        if !is_pattern(topic_id) {
+            return self.topics.get_mut(topic_id).map_or_else(Vec::new, |t| {
+                t.catch_up(opts).into_iter().map(|(f, e)| (topic_id.to_string(), f, e)).collect()
+            });
+        }
+        let mut ids: Vec<String> = self.topics
+            .keys()
+            .filter(|id| matches_pattern(topic_id, id))
+            .cloned()
+            .collect();
+        ids.sort();
+
+        let mut out = Vec::new();
+        for id in ids {
+            if let Some(t) = self.topics.get_mut(&id) {
+                out.extend(t.catch_up(opts).into_iter().map(|(f, e)| (id.clone(), f, e)));
+            }
+        }
+        out
+    }
+
+    /// Deliver `m` to every subscriber of `topic_id`, exact or
+    /// wildcard, as published by `publisher_id`, skipping the
+    /// subscription of its `sender`. Each subscriber receives the
+    /// message at most once, and each group once, through the next of
+    /// its members in order of subscriber id. A connection that cannot be
+    /// sent to loses all of its subscriptions; the message is counted in
+    /// the topic's `dropped` statistic, or given to the next member of a
+    /// group.
+    fn fan_out(&mut self,
+               topic_id: &str,
+               publisher_id: Option<&str>,
+               m: Message,
+               opts: PublishOptions) {
+        if !is_topic_id(topic_id) {
+            warn!("[router] Cannot publish to wildcard topic: {}", topic_id);
+            return;
+        }
+        if is_inbox(topic_id) {
+            return self.reply(topic_id, publisher_id, m, opts);
+        }
+
+        // An empty retained message only clears the retained value
+        if opts.retain && m.is_empty() {
+            if let Some(t) = self.topics.get_mut(topic_id) {
+                t.clear_retained();
+            }
+            return;
+        }
+
+        let expires_ms = opts.ttl_ms.map(|t| now_millis().saturating_add(t));
+        let frame = self.topic_mut(topic_id).publish(publisher_id, m, &opts);
+        let mut frames = Encoded::new(frame.clone());
+        let mut body = Body::new(&frame);
+
+        let mut dropped = 0;
+        let mut dead: HashSet<ConnectionId> = HashSet::new();
+        // Subscribers to deliver to at least once, once done with the
+        // topic and trie
+        let mut tracked: Vec<(String, Subscriber)> = Vec::new();
+        {
+            let mut delivered: HashSet<&String> = HashSet::new();
+            let exact = self.topics
+                .get(topic_id)
+                .into_iter()
+                .flat_map(|t| t.subscribers().iter());
+
+            let mut groups: BTreeMap<&str, Vec<(&String, &Subscriber)>> = BTreeMap::new();
+
+            for (id, s) in exact.chain(self.patterns.matches(topic_id)) {
+                // A subscription whose filter turns the message down
+                // leaves it to the others held under the same id
+                if Some(id) == opts.sender.as_ref() || !s.accepts(&mut body) ||
+                   !delivered.insert(id) {
+                    continue;
+                }
+                if let Some(ref g) = s.group {
+                    groups.entry(g).or_insert_with(Vec::new).push((id, s));
+                    continue;
+                }
+                if s.acks {
+                    tracked.push((id.clone(), s.clone()));
+                    continue;
+                }
+                if let Err(e) = s.deliver(&frames.get(s.encoding)) {
+                    warn!("[router] Dropping message of {} for connection {}: {}",
+                          topic_id,
+                          s.connection,
+                          e);
+                    dead.insert(s.connection);
+                    dropped += 1;
+                }
+            }
+
+            if !groups.is_empty() {
+                let cursors = self.cursors.entry(topic_id.to_string()).or_insert_with(HashMap::new);
+                for (g, mut members) in groups {
+                    members.sort_by(|a, b| a.0.cmp(b.0));
+                    let cursor = cursors.entry(g.to_string()).or_insert(0);
+                    let n = members.len();
+                    // Fall back on the following members if sending fails
+                    let mut sent = false;
+                    for i in 0..n {
+                        let at = (*cursor + i) % n;
+                        let (id, s) = members[at];
+                        if s.acks {
+                            tracked.push((id.clone(), s.clone()));
+                            *cursor = at + 1;
+                            sent = true;
+                            break;
+                        }
+                        match s.deliver(&frames.get(s.encoding)) {
+                            Ok(_) => {
+                                *cursor = at + 1;
+                                sent = true;
+                                break;
+                            }
+                            Err(e) => {
+                                debug!("[router] Unable to send to connection {}: {}",
+                                       s.connection,
+                                       e);
+                                dead.insert(s.connection);
+                            }
+                        }
+                    }
+                    if !sent {
+                        warn!("[router] Dropping message of {} for group {}", topic_id, g);
+                        dropped += 1;
+                    }
+                }
+            }
+        }
+
+        // Tracked deliveries that could not be sent are handed over to
+        // another member of their group when their connection is dropped
+        for (id, s) in tracked {
+            let connection = s.connection;
+            if !self.deliver_tracked(topic_id, &id, s, frame.clone(), expires_ms) {
+                dead.insert(connection);
+            }
+        }
+
+        if dropped > 0 {
+            if let Some(t) = self.topics.get_mut(topic_id) {
+                t.count_dropped(dropped);
+            }
+        }
+        for c in dead {
+            self.drop_connection(c);
+        }
+    }
+
+    /// Drop every subscription of `connection`, which can no longer be
+    /// sent to, in this shard and the others
+    fn drop_connection(&mut self, connection: ConnectionId) {
+        warn!("[router] Dropping the subscriptions of connection {}", connection);
+        self.disconnect(connection);
+        if let Some(ref router) = self.router {
+            if let Err(e) = router.send(RouterCommand::Disconnect(connection)) {
+                error!("[router] Unable to disconnect {} from other shards: {}", connection, e);
+            }
+        }
+    }
+
+    fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.ack_timeout_ms)
+    }
+
+    /// Deliver `frame`, published on `topic_id`, at least once, keeping
+    /// it until it is acked or expires at `expires_ms`. Returns false if
+    /// sending it failed.
+    fn deliver_tracked(&mut self,
+                       topic_id: &str,
+                       subscriber_id: &str,
+                       subscriber: Subscriber,
+                       frame: Frame,
+                       expires_ms: Option<u64>)
+                       -> bool {
+        let id = self.next_delivery;
+        self.next_delivery += 1;
+        let stamped = with_delivery(&frame, id, 1);
+        let sent = subscriber.deliver(&transcode(&stamped, subscriber.encoding));
+        if let Err(ref e) = sent {
+            warn!("[router] Unable to send to connection {}: {}", subscriber.connection, e);
+        }
+        let due = Instant::now() + self.ack_timeout();
+        self.pending.insert(id,
+                            Pending {
+                                topic_id: topic_id.to_string(),
+                                subscriber_id: subscriber_id.to_string(),
+                                subscriber: subscriber,
+                                frame: frame,
+                                attempts: 1,
+                                due: due,
+                                expires_ms: expires_ms,
+                            });
+        sent.is_ok()
+    }
+
+    /// Ack a delivery. Acks naming another topic or subscriber than the
+    /// delivery was made to are ignored.
+    pub fn ack(&mut self, topic_id: &str, subscriber_id: &str, delivery_id: u64) {
+        let matches = self.pending.get(&delivery_id).map_or(false, |p| {
+            p.topic_id == topic_id && p.subscriber_id == subscriber_id
+        });
+        if matches {
+            self.pending.remove(&delivery_id);
+        }
+    }
+
+    /// Deliver again the messages whose ack is overdue, and publish the
+    /// ones out of attempts on their dead-letter topic. Expired messages
+    /// are dropped instead. Does nothing if called again within
+    /// `REDELIVERY_CHECK_MS`.
+    pub fn redeliver(&mut self) {
+        if self.pending.is_empty() ||
+           self.last_redelivery.elapsed() < Duration::from_millis(REDELIVERY_CHECK_MS) {
+            return;
+        }
+        let now = Instant::now();
+        self.last_redelivery = now;
+
+        let due: Vec<u64> = self.pending
+            .iter()
+            .filter(|&(_, p)| p.due <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        let max_attempts = self.config.max_delivery_attempts;
+        let timeout = self.ack_timeout();
+        let now_ms = now_millis();
+        let mut dead: HashSet<ConnectionId> = HashSet::new();
+        for id in due {
+            let expired = self.pending
+                .get(&id)
+                .map_or(false, |p| p.expires_ms.map_or(false, |e| e <= now_ms));
+            if expired {
+                if let Some(p) = self.pending.remove(&id) {
+                    debug!("[router] Dropping expired delivery {} of {}", id, p.topic_id);
+                    if let Some(t) = self.topics.get_mut(&p.topic_id) {
+                        t.count_expired(1);
+                    }
+                }
+                continue;
+            }
+            let exhausted = self.pending.get(&id).map_or(false, |p| p.attempts >= max_attempts);
+            if exhausted {
+                if let Some(p) = self.pending.remove(&id) {
+                    self.dead_letter(p);
+                }
+                continue;
+            }
+            if let Some(p) = self.pending.get_mut(&id) {
+                p.attempts += 1;
+                p.due = now + timeout;
+                let f = transcode(&with_delivery(&p.frame, id, p.attempts), p.subscriber.encoding);
+                if let Err(e) = p.subscriber.deliver(&f) {
+                    debug!("[router] Unable to redeliver {} to connection {}: {}",
+                           id,
+                           p.subscriber.connection,
+                           e);
+                    dead.insert(p.subscriber.connection);
+                }
+            }
+        }
+        for c in dead {
+            self.drop_connection(c);
+        }
+    }
+
+    /// Open the reply-to inbox of a request, to be answered through
+    /// `responder` with the first reply, or with a timeout after
+    /// `timeout_ms`, the configured default if unset
+    pub fn await_reply(&mut self,
+                       inbox: String,
+                       correlation_id: String,
+                       responder: Responder,
+                       timeout_ms: Option<u64>) {
+        if !is_inbox(&inbox) {
+            warn!("[router] Cannot wait for a reply on {}", inbox);
+            return;
+        }
+        let timeout = timeout_ms.unwrap_or(self.config.request_timeout_ms);
+        self.inboxes.insert(inbox,
+                            Inbox {
+                                correlation_id: correlation_id,
+                                responder: responder,
+                                deadline: Instant::now() + Duration::from_millis(timeout),
+                            });
+    }
+
+    /// Answer the request waiting on `inbox` with `m`, and close the
+    /// inbox. Replies to closed inboxes, and replies without the
+    /// correlation id of the request, are dropped.
+    fn reply(&mut self, inbox: &str, publisher_id: Option<&str>, m: Message, opts: PublishOptions) {
+        let matches = match self.inboxes.get(inbox) {
+            Some(i) => opts.correlation_id.as_ref() == Some(&i.correlation_id),
+            None => {
+                debug!("[router] Dropping reply to closed inbox {}", inbox);
+                return;
+            }
+        };
+        if !matches {
+            debug!("[router] Dropping reply to {} without its correlation id", inbox);
+            return;
+        }
+        let pending = match self.inboxes.remove(inbox) {
+            Some(i) => i,
+            None => return,
+        };
+        let res = match m {
+            Message::Text(t) => {
+                Ok(serde_json::to_value(&TopicReply {
+                    correlation_id: pending.correlation_id,
+                    publisher_id: publisher_id.map(String::from),
+                    message: t,
+                }))
+            }
+            Message::Binary(_) => {
+                debug!("[router] Binary reply to {} cannot be returned", inbox);
+                Err(ResponseError::InvalidPayload)
+            }
+        };
+        pending.responder.respond(res);
+    }
+
+    /// Answer the requests past their deadline with a timeout, at most
+    /// once every `REDELIVERY_CHECK_MS`
+    pub fn expire_requests(&mut self) {
+        if self.inboxes.is_empty() ||
+           self.last_expiry.elapsed() < Duration::from_millis(REDELIVERY_CHECK_MS) {
+            return;
+        }
+        let now = Instant::now();
+        self.last_expiry = now;
+
+        let expired: Vec<String> = self.inboxes
+            .iter()
+            .filter(|&(_, i)| i.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            if let Some(i) = self.inboxes.remove(&id) {
+                debug!("[router] Request {} timed out", i.correlation_id);
+                i.responder.respond(Err(ResponseError::Timeout));
+            }
+        }
+    }
+
+    /// Publish a message that was never acked on the dead-letter topic
+    /// of its topic. Text messages are wrapped in a `TopicDeadLetter`;
+    /// binary ones go on as they were published.
+    fn dead_letter(&mut self, p: Pending) {
+        if p.topic_id.starts_with(&self.config.dead_letter_prefix[..]) {
+            warn!("[router] Dropping unacked message of dead-letter topic {}", p.topic_id);
+            return;
+        }
+        let topic_id = format!("{}{}", self.config.dead_letter_prefix, p.topic_id);
+        debug!("[router] Moving unacked message of {} to {}", p.topic_id, topic_id);
+
+        let m = match *p.frame {
+            Message::Text(ref t) => {
+                let params = serde_json::from_str::<Value>(t)
+                    .ok()
+                    .and_then(|n| n.as_object().and_then(|o| o.get("params")).cloned())
+                    .unwrap_or(Value::Null);
+                let letter = TopicDeadLetter {
+                    topic_id: p.topic_id.clone(),
+                    subscriber_id: p.subscriber_id.clone(),
+                    attempts: p.attempts,
+                    message: params,
+                };
+                match serde_json::to_string(&letter) {
+                    Ok(s) => Message::Text(s),
+                    Err(e) => {
+                        error!("[router] Unable to serialize dead letter: {}", e);
+                        return;
+                    }
+                }
+            }
+            Message::Binary(ref b) => {
+                match MessageRequestBinary::decode(b.clone()) {
+                    Some(r) => Message::Binary(r.payload),
+                    None => return,
+                }
+            }
+        };
+
+        // The dead-letter topic may live in another shard
+        if let Some(ref r) = self.router {
+            if r.send(RouterCommand::Broadcast(topic_id, m)).is_err() {
+                error!("[router] Unable to publish dead letter of {}", p.topic_id);
+            }
+            return;
+        }
+        self.broadcast(&topic_id, m);
+    }
+
+    /// Delete a topic, telling its subscribers with a `topic.deleted`
+    /// notification. Wildcard subscriptions matching it are kept.
+    /// Returns false if there was no such topic.
+    pub fn delete_topic(&mut self, topic_id: &str) -> bool {
+        let mut topic = match self.topics.remove(topic_id) {
+            Some(t) => t,
+            None => return false,
+        };
+        debug!("[router] Deleting topic {}", topic_id);
+        self.cursors.remove(topic_id);
+        self.pending.retain(|_, p| p.topic_id != topic_id);
+
+        let n = MessageNotification::new("topic.deleted", TopicKey { topic_id: topic.id() });
+        let mut frames = match serde_json::to_string(&n) {
+            Ok(t) => Some(Encoded::new(Arc::new(Message::Text(t)))),
+            Err(e) => {
+                error!("[router] Unable to serialize deletion of {}: {}", topic_id, e);
+                None
+            }
+        };
+        for (id, s) in topic.subscribers() {
+            if let Some(ref mut f) = frames {
+                let _ = s.deliver(&f.get(s.encoding));
+            }
+            let now_empty = match self.connections.get_mut(&s.connection) {
+                Some(subs) => {
+                    subs.remove(&(topic_id.to_string(), id.clone()));
+                    subs.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.connections.remove(&s.connection);
+            }
+        }
+        topic.destroy();
+        true
+    }
+
+    /// Up to `limit` ids of `visible` topics starting with `prefix` and
+    /// coming after `after`, in order
+    pub fn list_topics(&self,
+                       prefix: &str,
+                       after: Option<&str>,
+                       limit: usize,
+                       visible: &Fn(&str) -> bool)
+                       -> Vec<String> {
+        let mut ids: Vec<String> = self.topics
+            .keys()
+            .filter(|id| id.starts_with(prefix) && after.map_or(true, |a| &id[..] > a))
+            .filter(|id| visible(id))
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.truncate(limit);
+        ids
+    }
+
+    /// State of a topic, if it exists
+    pub fn describe_topic(&mut self, topic_id: &str) -> Option<TopicInfo> {
+        let patterns = self.patterns.matches(topic_id).len();
+        self.topics.get_mut(topic_id).map(|t| t.info(patterns))
+    }
+
+    pub fn send(&mut self,
+                topic_id: &str,
+                publisher_id: Option<&str>,
+                m: Message,
+                opts: PublishOptions) {
+        self.fan_out(topic_id, publisher_id, m, opts);
+    }
+
+    pub fn broadcast(&mut self, topic_id: &str, m: Message) {
+        self.fan_out(topic_id, None, m, PublishOptions::default());
+    }
+
+    pub fn parse_command(&mut self, c: RouterCommand) {
+        match c {
+            RouterCommand::CreateTopic(tid, o) => self.create_topic(tid, o),
+            RouterCommand::Subscribe(tid, sid, s, o, r) => {
+                let _ = r.send(self.subscribe(tid, sid, s, o));
+            }
+            RouterCommand::Broadcast(tid, m) => self.broadcast(&tid, m),
+            RouterCommand::Send(tid, sid, m, o) => {
+                self.send(&tid, sid.as_ref().map(String::as_str), m, o)
+            }
+            RouterCommand::Unsubscribe(tid, sid, cid) => self.unsubscribe(&tid, &sid, cid),
+            RouterCommand::Disconnect(cid) => self.disconnect(cid),
+            RouterCommand::DeleteTopic(tid, r) => {
+                let _ = r.send(self.delete_topic(&tid));
+            }
+            RouterCommand::ListTopics(prefix, after, limit, visible, r) => {
+                let after = after.as_ref().map(|a| &a[..]);
+                let _ = r.send(self.list_topics(&prefix, after, limit, &*visible));
+            }
+            RouterCommand::DescribeTopic(tid, r) => {
+                let _ = r.send(self.describe_topic(&tid));
+            }
+            RouterCommand::Ack(tid, sid, d) => self.ack(&tid, &sid, d),
+            RouterCommand::AwaitReply(tid, c, r, t) => self.await_reply(tid, c, r, t),
+        }
+    }
+}
+
+/// Handle to a sharded router. Topics are split over the shards by
+/// hashing their ids, so that all commands for a topic go to the same
+/// shard and its messages stay in order. Cloning it is cheap; every
+/// clone feeds the same shards.
+#[derive(Clone)]
+pub struct Router {
+    shards: Vec<mpsc::Sender<RouterCommand>>,
+}
+
+impl Router {
+    /// Start `config.shards` registries, each on its own thread. Durable
+    /// topics in `store` are loaded by the shard that owns them.
+    pub fn spawn(config: RouterConfig, store: Option<Arc<DataStore>>) -> Self {
+        let count = if config.shards == 0 { 1 } else { config.shards };
+        let (shards, receivers): (Vec<_>, Vec<_>) = (0..count)
+            .map(|_| channel::<RouterCommand>())
+            .unzip();
+        let router = Router { shards: shards };
+
+        for (i, rx) in receivers.into_iter().enumerate() {
+            let conf = config.clone();
+            let store = store.clone();
+            let router = router.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("router-{}", i))
+                .spawn(move || {
+                    let mut reg = match store {
+                        Some(s) => {
+                            Registry::with_store_filter(conf, s, |t| shard_of(t, count) == i)
+                        }
+                        None => Registry::with_config(conf),
+                    };
+                    reg.router = Some(router);
+                    let tick = Duration::from_millis(REDELIVERY_CHECK_MS);
+                    loop {
+                        match rx.recv_timeout(tick) {
+                            Ok(c) => reg.parse_command(c),
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                        reg.redeliver();
+                        reg.expire_requests();
+                    }
+                    debug!("[router] Shard {} stopped", i);
+                });
+            if let Err(e) = spawned {
+                panic!("Unable to start router shard {}: {}", i, e);
+            }
+        }
+        debug!("[router] Started {} shards", count);
+        router
+    }
+
+    /// Number of shards topics are split over
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Queue a command on the shard owning its topic, or on every shard
+    /// for wildcard subscriptions, disconnects and listings. Every shard
+    /// replies to a listing with the topics it owns.
+    pub fn send(&self, c: RouterCommand) -> Result<(), SendError<RouterCommand>> {
+        let shard = match c {
+            RouterCommand::CreateTopic(ref tid, _) |
+            RouterCommand::Send(ref tid, _, _, _) |
+            RouterCommand::Broadcast(ref tid, _) |
+            RouterCommand::DeleteTopic(ref tid, _) |
+            RouterCommand::DescribeTopic(ref tid, _) |
+            RouterCommand::Ack(ref tid, _, _) |
+            RouterCommand::AwaitReply(ref tid, _, _, _) => Some(tid.clone()),
+            RouterCommand::Subscribe(ref tid, _, _, _, _) |
+            RouterCommand::Unsubscribe(ref tid, _, _) => {
+                if is_pattern(tid) { None } else { Some(tid.clone()) }
+            }
+            RouterCommand::Disconnect(_) |
+            RouterCommand::ListTopics(..) => None,
+        };
+        match shard {
+            Some(tid) => self.shards[shard_of(&tid, self.shards.len())].send(c),
+            None => {
+                for tx in &self.shards {
+                    try!(tx.send(c.clone()));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Index of the shard, out of `count`, that owns `topic_id`
+fn shard_of(topic_id: &str, count: usize) -> usize {
+    let mut h = DefaultHasher::new();
+    topic_id.hash(&mut h);
+    (h.finish() % count as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use datastore::{DiskOptions, DiskStore};
+    use testing::{RECV_TIMEOUT_MS, connect, is_silent, scratch};
+
+    /// A subscriber on a connection to a local server, along with the
+    /// frames its client gets
+    fn client(connection: ConnectionId) -> (Subscriber, mpsc::Receiver<Message>) {
+        let (sender, frames) = connect();
+        (Subscriber::new(connection, sender, Encoding::Json), frames)
+    }
+
+    /// Params of the next notification on `frames`
+    fn next_params(frames: &mpsc::Receiver<Message>) -> Value {
+        let m = frames.recv_timeout(Duration::from_millis(RECV_TIMEOUT_MS)).unwrap();
+        let n: Value = serde_json::from_str(m.as_text().unwrap()).unwrap();
+        n.as_object().and_then(|o| o.get("params")).cloned().unwrap()
+    }
+
+    fn field(params: &Value, name: &str) -> u64 {
+        params.as_object().and_then(|o| o.get(name)).and_then(|v| v.as_u64()).unwrap()
+    }
+
+    fn publish(reg: &mut Registry, topic_id: &str, message: &str) {
+        reg.send(topic_id, None, Message::text(message), PublishOptions::default());
+    }
+
+    /// A sender for subscribers that are matched but never sent to
+    fn idle_sender() -> Sender {
+        ws::WebSocket::new(|_| |_| Ok(())).unwrap().broadcaster()
+    }
+
+    fn subscriber(connection: ConnectionId) -> Subscriber {
+        Subscriber::new(connection, idle_sender(), Encoding::Json)
+    }
+
+    /// Ids of the subscribers of `trie` matching `topic_id`, sorted
+    fn matched(trie: &SubscriptionTrie, topic_id: &str) -> Vec<String> {
+        let mut ids: Vec<String> = trie.matches(topic_id)
+            .into_iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn validates_patterns() {
+        assert!(is_valid_pattern("a/b"));
+        assert!(is_valid_pattern("a/+/c"));
+        assert!(is_valid_pattern("a/#"));
+        assert!(is_valid_pattern("#"));
+        assert!(is_valid_pattern("+/+"));
+        assert!(!is_valid_pattern("a/#/c"));
+        assert!(!is_valid_pattern("a/b+"));
+        assert!(!is_valid_pattern("a/#b"));
+        assert!(!is_valid_pattern("#/#"));
+    }
+
+    #[test]
+    fn validates_topic_ids() {
+        assert!(is_topic_id("a/b"));
+        assert!(is_topic_id("a"));
+        assert!(!is_topic_id("a/+"));
+        assert!(!is_topic_id("a/b+"));
+        assert!(!is_topic_id("#a/b"));
+    }
+
+    #[test]
+    fn refuses_topic_ids_with_wildcard_characters() {
+        let mut reg = Registry::new();
+        reg.create_topic("a/b+".to_string(), TopicOptions::default());
+        publish(&mut reg, "a/b+", "hello");
+        publish(&mut reg, "a/#b", "hello");
+        assert!(reg.topics.is_empty());
+        assert_eq!(reg.subscribe("a/b+".to_string(),
+                                 "s".to_string(),
+                                 subscriber(1),
+                                 SubscribeOptions::default())
+                       .err(),
+                   Some(ResponseError::InvalidPayload));
+    }
+
+    #[test]
+    fn matches_single_level_wildcards() {
+        assert!(matches_pattern("a/+/c", "a/b/c"));
+        assert!(matches_pattern("+/+", "a/b"));
+        assert!(!matches_pattern("a/+/c", "a/c"));
+        assert!(!matches_pattern("a/+/c", "a/b/b/c"));
+        assert!(!matches_pattern("a/+", "a/b/c"));
+        assert!(matches_pattern("a/+", "a/"));
+    }
+
+    #[test]
+    fn matches_multi_level_wildcards() {
+        assert!(matches_pattern("#", "a"));
+        assert!(matches_pattern("#", "a/b/c"));
+        assert!(matches_pattern("a/#", "a/b/c"));
+        assert!(matches_pattern("a/#", "a"));
+        assert!(matches_pattern("a/+/#", "a/b/c/d"));
+        assert!(!matches_pattern("a/#", "b/c"));
+        assert!(!matches_pattern("a/+/#", "a"));
+    }
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(matches_pattern("a/b", "a/b"));
+        assert!(!matches_pattern("a/b", "a/b/c"));
+        assert!(!matches_pattern("a/b/c", "a/b"));
+        assert!(!matches_pattern("a/b", "a/c"));
+    }
+
+    #[test]
+    fn covers_patterns() {
+        assert!(covers_pattern("#", "a/+/#"));
+        assert!(covers_pattern("a/#", "a/b/#"));
+        assert!(covers_pattern("a/#", "a/+"));
+        assert!(covers_pattern("a/+", "a/b"));
+        assert!(covers_pattern("a/+", "a/+"));
+        assert!(covers_pattern("a/b", "a/b"));
+        assert!(!covers_pattern("a/b", "a/+"));
+        assert!(!covers_pattern("a/+", "a/#"));
+        assert!(!covers_pattern("a/+", "a/b/c"));
+        assert!(!covers_pattern("a/+/c", "a/b"));
+        assert!(!covers_pattern("b/#", "a/b"));
+    }
+
+    #[test]
+    fn trie_matches_every_pattern_of_a_topic() {
+        let mut trie = SubscriptionTrie::new();
+        trie.insert("a/+/c", "plus".to_string(), subscriber(1));
+        trie.insert("a/#", "hash".to_string(), subscriber(2));
+        trie.insert("#", "all".to_string(), subscriber(3));
+        trie.insert("+/b/+", "middle".to_string(), subscriber(4));
+        trie.insert("a/b/c", "exact".to_string(), subscriber(5));
+
+        assert_eq!(matched(&trie, "a/b/c"), vec!["all", "exact", "hash", "middle", "plus"]);
+        assert_eq!(matched(&trie, "a/x/c"), vec!["all", "hash", "plus"]);
+        assert_eq!(matched(&trie, "a"), vec!["all", "hash"]);
+        assert_eq!(matched(&trie, "x/b/y"), vec!["all", "middle"]);
+        assert_eq!(matched(&trie, "x"), vec!["all"]);
+    }
+
+    #[test]
+    fn trie_removes_and_prunes_subscriptions() {
+        let mut trie = SubscriptionTrie::new();
+        trie.insert("a/+/c", "one".to_string(), subscriber(1));
+        trie.insert("a/+/c", "two".to_string(), subscriber(2));
+        trie.insert("a/#", "three".to_string(), subscriber(3));
+
+        assert_eq!(trie.get("a/+/c", "two").map(|s| s.connection), Some(2));
+        assert!(trie.get("a/+", "two").is_none());
+        assert!(trie.remove("a/+/c", "nobody").is_none());
+        assert!(trie.remove("a/+", "one").is_none());
+
+        assert_eq!(trie.remove("a/+/c", "one").map(|s| s.connection), Some(1));
+        assert_eq!(matched(&trie, "a/b/c"), vec!["three", "two"]);
+        assert!(trie.remove("a/+/c", "two").is_some());
+        assert!(trie.remove("a/#", "three").is_some());
+        assert!(matched(&trie, "a/b/c").is_empty());
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn groups_take_turns_in_order_of_subscriber_id() {
+        let mut reg = Registry::new();
+        let (a, a_frames) = client(1);
+        let (b, b_frames) = client(2);
+        let (c, c_frames) = client(3);
+        let (solo, solo_frames) = client(4);
+        let group = Some("workers".to_string());
+        // Subscribed out of order, to check turns follow the ids
+        for (id, s) in vec![("c", c), ("a", a), ("b", b)] {
+            let s = s.in_group(group.clone());
+            reg.subscribe("jobs".to_string(), id.to_string(), s, SubscribeOptions::default())
+                .unwrap();
+        }
+        reg.subscribe("jobs".to_string(),
+                       "solo".to_string(),
+                       solo,
+                       SubscribeOptions::default())
+            .unwrap();
+
+        for i in 0..6 {
+            publish(&mut reg, "jobs", &format!("job {}", i));
+        }
+
+        for &(frames, first) in &[(&a_frames, 1), (&b_frames, 2), (&c_frames, 3)] {
+            assert_eq!(field(&next_params(frames), "seq"), first);
+            assert_eq!(field(&next_params(frames), "seq"), first + 3);
+            assert!(is_silent(frames));
+        }
+        for seq in 1..7 {
+            assert_eq!(field(&next_params(&solo_frames), "seq"), seq);
+        }
+    }
+
+    #[test]
+    fn groups_skip_members_that_leave() {
+        let mut reg = Registry::new();
+        let (a, a_frames) = client(1);
+        let (b, b_frames) = client(2);
+        let group = Some("workers".to_string());
+        for (id, s) in vec![("a", a), ("b", b)] {
+            let s = s.in_group(group.clone());
+            reg.subscribe("jobs".to_string(), id.to_string(), s, SubscribeOptions::default())
+                .unwrap();
+        }
+
+        publish(&mut reg, "jobs", "job 1");
+        reg.unsubscribe("jobs", "b", 2);
+        publish(&mut reg, "jobs", "job 2");
+        publish(&mut reg, "jobs", "job 3");
+
+        assert_eq!(field(&next_params(&a_frames), "seq"), 1);
+        assert_eq!(field(&next_params(&a_frames), "seq"), 2);
+        assert_eq!(field(&next_params(&a_frames), "seq"), 3);
+        assert!(is_silent(&b_frames));
+    }
+
+    /// A sender whose queue is full, so that sending to it fails
+    fn full_sender() -> Sender {
+        let settings = ws::Settings {
+            max_connections: 1,
+            queue_size: 1,
+            ..ws::Settings::default()
+        };
+        let sender = ws::Builder::new()
+            .with_settings(settings)
+            .build(|_| |_| Ok(()))
+            .unwrap()
+            .broadcaster();
+        while sender.send("filler").is_ok() {}
+        sender
+    }
+
+    #[test]
+    fn drops_connections_that_cannot_be_sent_to() {
+        let mut reg = Registry::new();
+        let stuck = Subscriber::new(1, full_sender(), Encoding::Json);
+        let (live, frames) = client(2);
+        for (tid, id, s) in vec![("t", "stuck", stuck.clone()), ("u/+", "stuck", stuck),
+                                 ("t", "live", live)] {
+            reg.subscribe(tid.to_string(), id.to_string(), s, SubscribeOptions::default())
+                .unwrap();
+        }
+
+        publish(&mut reg, "t", "hello");
+
+        assert_eq!(field(&next_params(&frames), "seq"), 1);
+        assert!(reg.subscription("t", "stuck").is_none());
+        assert!(reg.subscription("u/+", "stuck").is_none());
+        assert!(!reg.connections.contains_key(&1));
+        assert_eq!(reg.topics["t"].dropped(), 1);
+    }
+
+    #[test]
+    fn disconnecting_drops_subscriptions_and_pending_deliveries() {
+        let mut reg = acking_registry(60_000, 5);
+        let (s, frames) = client(1);
+        for tid in vec!["t", "u/+"] {
+            reg.subscribe(tid.to_string(),
+                           "s".to_string(),
+                           s.clone().with_acks(true),
+                           SubscribeOptions::default())
+                .unwrap();
+        }
+        publish(&mut reg, "t", "hello");
+        publish(&mut reg, "u/v", "hello");
+        next_params(&frames);
+        next_params(&frames);
+        assert_eq!(reg.pending.len(), 2);
+
+        reg.disconnect(1);
+
+        assert!(reg.subscription("t", "s").is_none());
+        assert!(reg.subscription("u/+", "s").is_none());
+        assert!(reg.connections.is_empty());
+        assert!(reg.pending.is_empty());
+    }
+
+    #[test]
+    fn hands_pending_deliveries_over_to_the_group() {
+        let mut reg = acking_registry(60_000, 5);
+        let (a, a_frames) = client(1);
+        let (b, b_frames) = client(2);
+        let group = Some("workers".to_string());
+        for (id, s) in vec![("a", a), ("b", b)] {
+            let s = s.in_group(group.clone()).with_acks(true);
+            reg.subscribe("jobs".to_string(), id.to_string(), s, SubscribeOptions::default())
+                .unwrap();
+        }
+        publish(&mut reg, "jobs", "job 1");
+        assert_eq!(field(&next_params(&a_frames), "seq"), 1);
+
+        // Left unacked by a, the job goes to b
+        reg.disconnect(1);
+        let handed = next_params(&b_frames);
+        assert_eq!(field(&handed, "seq"), 1);
+        assert_eq!(field(&handed, "attempt"), 1);
+        assert_eq!(reg.pending.len(), 1);
+
+        // With no member left, it is dropped
+        reg.unsubscribe("jobs", "b", 2);
+        assert!(reg.pending.is_empty());
+        assert!(is_silent(&a_frames));
+    }
+
+    fn message(params: &Value) -> String {
+        params.as_object().and_then(|o| o.get("message")).and_then(|v| v.as_str()).unwrap().into()
+    }
+
+    #[test]
+    fn replays_durable_topics_after_a_restart() {
+        let dir = scratch("router-durable");
+        let open = || -> Arc<DataStore> {
+            Arc::new(DiskStore::open(&dir, DiskOptions::default()).unwrap())
+        };
+        {
+            let mut reg = Registry::with_store(RouterConfig::default(), open());
+            let opts = TopicOptions { durable: Some(true), ..TopicOptions::default() };
+            reg.create_topic("log".to_string(), opts);
+            for i in 1..4 {
+                publish(&mut reg, "log", &format!("before {}", i));
+            }
+        }
+
+        let mut reg = Registry::with_store(RouterConfig::default(), open());
+        assert_eq!(reg.topics.get("log").map(|t| t.last_seq()), Some(3));
+        publish(&mut reg, "log", "after");
+
+        let (s, frames) = client(1);
+        let opts = SubscribeOptions { from_seq: Some(1), ..SubscribeOptions::default() };
+        reg.subscribe("log".to_string(), "s".to_string(), s, opts).unwrap();
+        for (seq, text) in vec![(1, "before 1"), (2, "before 2"), (3, "before 3"), (4, "after")] {
+            let params = next_params(&frames);
+            assert_eq!(field(&params, "seq"), seq);
+            assert_eq!(message(&params), text);
+        }
+        assert!(is_silent(&frames));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delivers_messages_with_the_longest_ttl() {
+        let mut reg = Registry::new();
+        let (s, frames) = client(1);
+        reg.subscribe("t".to_string(), "s".to_string(), s, SubscribeOptions::default())
+            .unwrap();
+        let opts = PublishOptions { ttl_ms: Some(u64::max_value()), ..PublishOptions::default() };
+        reg.send("t", None, Message::text("forever"), opts);
+        assert_eq!(field(&next_params(&frames), "seq"), 1);
+    }
+
+    /// A registry redelivering after `ack_timeout_ms`, up to
+    /// `max_attempts` times
+    fn acking_registry(ack_timeout_ms: u64, max_attempts: u32) -> Registry {
+        Registry::with_config(RouterConfig {
+            ack_timeout_ms: ack_timeout_ms,
+            max_delivery_attempts: max_attempts,
+            ..RouterConfig::default()
+        })
+    }
+
+    /// Let the ack timeout and the redelivery check pass, then redeliver
+    fn redeliver_later(reg: &mut Registry) {
+        thread::sleep(Duration::from_millis(REDELIVERY_CHECK_MS + 20));
+        reg.redeliver();
+    }
+
+    #[test]
+    fn redelivers_until_acked() {
+        let mut reg = acking_registry(10, 5);
+        let (s, frames) = client(1);
+        reg.subscribe("t".to_string(),
+                       "s".to_string(),
+                       s.with_acks(true),
+                       SubscribeOptions::default())
+            .unwrap();
+        publish(&mut reg, "t", "hello");
+
+        let first = next_params(&frames);
+        let delivery_id = field(&first, "delivery_id");
+        assert_eq!(field(&first, "attempt"), 1);
+
+        // Acks for another subscriber or topic leave the delivery pending
+        reg.ack("t", "other", delivery_id);
+        reg.ack("u", "s", delivery_id);
+        redeliver_later(&mut reg);
+        let again = next_params(&frames);
+        assert_eq!(field(&again, "delivery_id"), delivery_id);
+        assert_eq!(field(&again, "attempt"), 2);
+        assert_eq!(field(&again, "seq"), field(&first, "seq"));
+
+        reg.ack("t", "s", delivery_id);
+        redeliver_later(&mut reg);
+        assert!(is_silent(&frames));
+    }
+
+    #[test]
+    fn dead_letters_after_the_last_attempt() {
+        let mut reg = acking_registry(10, 2);
+        let (s, frames) = client(1);
+        let (watcher, letters) = client(2);
+        reg.subscribe("t".to_string(),
+                       "s".to_string(),
+                       s.with_acks(true),
+                       SubscribeOptions::default())
+            .unwrap();
+        reg.subscribe("dead-letter/t".to_string(),
+                       "watcher".to_string(),
+                       watcher,
+                       SubscribeOptions::default())
+            .unwrap();
+        publish(&mut reg, "t", "hello");
+
+        assert_eq!(field(&next_params(&frames), "attempt"), 1);
+        redeliver_later(&mut reg);
+        assert_eq!(field(&next_params(&frames), "attempt"), 2);
+        redeliver_later(&mut reg);
+        assert!(is_silent(&frames));
+
+        let params = next_params(&letters);
+        let letter = params.as_object()
+            .and_then(|o| o.get("message"))
+            .and_then(|m| m.as_str())
+            .and_then(|m| serde_json::from_str::<TopicDeadLetter>(m).ok())
+            .unwrap();
+        assert_eq!(letter.topic_id, "t");
+        assert_eq!(letter.subscriber_id, "s");
+        assert_eq!(letter.attempts, 2);
+
+        // Nothing is left to redeliver
+        redeliver_later(&mut reg);
+        assert!(is_silent(&frames));
+        assert!(is_silent(&letters));
     }
 }
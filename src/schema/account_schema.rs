@@ -1,3 +1,8 @@
+/// Key of an account in the datastore
+pub fn account_key(id: &str) -> String {
+    format!("account:{}", id)
+}
+
 /// Data structure for registering new account
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewAccount {
@@ -11,6 +16,39 @@ impl NewAccount {
 
     /// Gives the key to use for db sets and gets
     pub fn get_key(&self) -> String {
-        format!("account:{}", self.id)
+        account_key(&self.id)
     }
 }
+
+/// Credentials to log in with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountLogin {
+    pub id: String,
+    pub password: String,
+}
+
+/// Replace the password of the account the connection is logged in as
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountPasswordChange {
+    pub id: String,
+    /// Current password
+    pub password: String,
+    pub new_password: String,
+}
+
+/// An account as kept in the datastore
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub id: String,
+    /// Salted Argon2i hash of the password, in its encoded form
+    pub password_hash: String,
+    /// Creation time, in milliseconds since the Unix epoch
+    pub created_ms: u64,
+}
+
+/// An account as shown to clients
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountInfo {
+    pub id: String,
+    pub created_ms: u64,
+}
@@ -7,14 +7,154 @@ use std::collections::HashMap;
 pub struct Config {
     /// Services configuration
     pub services: HashMap<String, Service>,
+
+    /// Router configuration
+    #[serde(default)]
+    pub router: RouterConfig,
+
+    /// Persistent storage configuration
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Access control for topics
+    #[serde(default)]
+    pub acl: AclConfig,
 }
 
 impl Config {
     pub fn new() -> Self {
-        Config { services: HashMap::new() }
+        Config {
+            services: HashMap::new(),
+            router: RouterConfig::default(),
+            storage: StorageConfig::default(),
+            acl: AclConfig::default(),
+        }
+    }
+}
+
+/// Router configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouterConfig {
+    /// Number of messages each topic keeps for replay by default
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+
+    /// Maximum age, in milliseconds, of messages kept for replay
+    pub history_age_ms: Option<u64>,
+
+    /// Topic ids or patterns whose history is written to disk. Needs
+    /// `storage.path` to be set.
+    #[serde(default)]
+    pub durable_topics: Vec<String>,
+
+    /// Number of shards, each with its own thread, that topics are
+    /// split over
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+
+    /// Milliseconds to wait for the ack of a message delivered at least
+    /// once, before delivering it again
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+
+    /// Times a message is delivered at least once without being acked
+    /// before it goes to the dead-letter topic
+    #[serde(default = "default_max_delivery_attempts")]
+    pub max_delivery_attempts: u32,
+
+    /// Prefix put before a topic id to name its dead-letter topic
+    #[serde(default = "default_dead_letter_prefix")]
+    pub dead_letter_prefix: String,
+
+    /// Milliseconds to wait for the reply to a `topic.request` that
+    /// does not give its own timeout
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig {
+            history_size: default_history_size(),
+            history_age_ms: None,
+            durable_topics: Vec::new(),
+            shards: default_shards(),
+            ack_timeout_ms: default_ack_timeout_ms(),
+            max_delivery_attempts: default_max_delivery_attempts(),
+            dead_letter_prefix: default_dead_letter_prefix(),
+            request_timeout_ms: default_request_timeout_ms(),
+        }
     }
 }
 
+/// Persistent storage configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// Directory to keep data in. Everything is kept in memory only if
+    /// this is not set.
+    pub path: Option<String>,
+
+    /// Size in bytes after which a new log segment is started
+    #[serde(default = "default_segment_size")]
+    pub segment_size: u64,
+
+    /// Seconds between housekeeping runs (expiry and compaction). 0
+    /// disables them.
+    #[serde(default = "default_maintenance_interval")]
+    pub maintenance_interval: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            path: None,
+            segment_size: default_segment_size(),
+            maintenance_interval: default_maintenance_interval(),
+        }
+    }
+}
+
+/// Access control for topics
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct AclConfig {
+    /// Check the rights of connections on topics. When off, every
+    /// connection may do anything.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Identities allowed to change the rules with the `acl.*` methods,
+    /// named `account:{id}` or `token:{subject}`. Accounts named here
+    /// can only be registered by an admin. While access control is on,
+    /// they are also the only identities the `datastore` service serves.
+    #[serde(default)]
+    pub admins: Vec<String>,
+
+    /// Rules granting rights on topics. Rules granted or revoked with
+    /// the `acl.*` methods are not written back here, and are lost on
+    /// restart.
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+/// Grant of rights on topics to an identity, a role or everyone
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclRule {
+    /// Topic id or pattern of the topics the rights are on
+    pub topic: String,
+
+    /// Identity the rule applies to, named `account:{id}` or
+    /// `token:{subject}`
+    pub identity: Option<String>,
+
+    /// Role the rule applies to, as given in the `role` or `roles`
+    /// claim of a token. A rule with neither identity nor role applies
+    /// to every connection, authenticated or not.
+    pub role: Option<String>,
+
+    /// Rights granted: `publish`, `subscribe` and `create`
+    pub allow: Vec<String>,
+}
+
 /// Individual service configuration
 /// TODO: Make more generic and allow more information.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -26,6 +166,17 @@ pub struct Service {
     /// IP to listen on
     #[serde(default = "default_port")]
     pub port: i64,
+
+    /// Accept `wss://` connections with this certificate, instead of
+    /// plain `ws://` ones. Needs the `ssl` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+
+    /// Authenticate connections with the bearer tokens they present in
+    /// the opening handshake. The `datastore` service takes the auth of
+    /// the `api` service if it has none, and always requires a token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
 }
 
 impl Service {
@@ -33,6 +184,8 @@ impl Service {
         Service {
             ip: ip,
             port: port,
+            tls: None,
+            auth: None,
         }
     }
 
@@ -41,6 +194,42 @@ impl Service {
     }
 }
 
+/// Certificate of a service accepting `wss://` connections. Both files
+/// are read again when the process gets a `SIGHUP`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, followed by its chain
+    pub cert_path: String,
+
+    /// PEM file with the private key of the certificate
+    pub key_path: String,
+}
+
+/// Key the bearer tokens of a service are signed with. Exactly one of
+/// `secret` and `public_key_path` is set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Shared secret of tokens signed with HMAC-SHA256 (`HS256`)
+    pub secret: Option<String>,
+
+    /// PEM file with the RSA public key of tokens signed with `RS256`.
+    /// Needs the `ssl` feature.
+    pub public_key_path: Option<String>,
+
+    /// Refuse connections that present no token
+    #[serde(default)]
+    pub required: bool,
+
+    /// Seconds of clock difference tolerated when checking the `exp`
+    /// and `nbf` of tokens
+    #[serde(default = "default_auth_leeway_s")]
+    pub leeway_s: u64,
+
+    /// Accept tokens without an `exp` claim, which never expire
+    #[serde(default)]
+    pub allow_without_exp: bool,
+}
+
 fn default_ip() -> String {
     "127.0.0.1".to_string()
 }
@@ -48,3 +237,39 @@ fn default_ip() -> String {
 fn default_port() -> i64 {
     60000
 }
+
+fn default_history_size() -> usize {
+    100
+}
+
+fn default_shards() -> usize {
+    4
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_max_delivery_attempts() -> u32 {
+    5
+}
+
+fn default_dead_letter_prefix() -> String {
+    "dead-letter/".to_string()
+}
+
+fn default_request_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_auth_leeway_s() -> u64 {
+    60
+}
+
+fn default_segment_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_maintenance_interval() -> u64 {
+    60
+}
@@ -0,0 +1,29 @@
+/// Data structures for the datastore API
+
+/// Get or delete a record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataKey {
+    pub key: String,
+}
+
+/// Store a record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataPut {
+    pub key: String,
+    pub value: String,
+    /// Time to live in milliseconds. Records without one never expire.
+    pub ttl_ms: Option<u64>,
+}
+
+/// List records by key prefix
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataScan {
+    pub prefix: String,
+}
+
+/// A single record returned by a scan
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataRecord {
+    pub key: String,
+    pub value: String,
+}
@@ -1,45 +1,239 @@
 /// Schema for messages
+///
+/// Requests and responses follow JSON-RPC 2.0.
 
+use std::fmt;
 use std::string::String;
 
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+
+/// JSON-RPC version spoken by unicorn
+pub const JSONRPC_VERSION: &'static str = "2.0";
+
+/// A request with untyped params. Requests without an `id` are
+/// notifications, and are not answered. An `id` of `null` reads as `None`
+/// here too, but is answered, so look at the request object to tell.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessageRequest {
-    pub method: String
+    pub jsonrpc: String,
+    pub method: String,
+    pub id: Option<Value>,
+    pub params: Option<Value>,
 }
 
+/// A request with typed params
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessageRequestText<T> {
+    pub jsonrpc: String,
     pub method: String,
-    pub payload: Option<T>,
+    pub id: Option<Value>,
+    pub params: Option<T>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Largest header a binary frame can carry
+pub const MAX_BINARY_HEADER: usize = 0xffff;
+
+/// First byte of the binary frames laid out as `MessageRequestBinary` on
+/// MessagePack and CBOR connections. No envelope, an object or a batch
+/// array, can start with it: it is the CBOR `break` code, and a negative
+/// integer in MessagePack.
+pub const BINARY_MARKER: u8 = 0xff;
+
+/// A request sent as a binary frame, carrying raw bytes alongside the
+/// request object.
+///
+/// On the wire, the frame starts with the length of the header as a
+/// little endian `u16`, followed by the header, a JSON-RPC request
+/// object, and then the payload, which takes up the rest of the frame.
+/// On MessagePack and CBOR connections, where envelopes travel in binary
+/// frames too, the frame is preceded by `BINARY_MARKER`.
+#[derive(Clone, Debug)]
 pub struct MessageRequestBinary {
-    pub method: String,
+    pub header: Value,
     pub payload: Vec<u8>,
 }
 
+impl MessageRequestBinary {
+    /// Split a binary frame into its header and payload. Returns `None`
+    /// if the frame is truncated or the header is not valid JSON.
+    pub fn decode(mut frame: Vec<u8>) -> Option<Self> {
+        if frame.len() < 2 {
+            return None;
+        }
+        let end = 2 + (frame[0] as usize | (frame[1] as usize) << 8);
+        if frame.len() < end {
+            return None;
+        }
+        let header = match serde_json::from_slice::<Value>(&frame[2..end]) {
+            Ok(h) => h,
+            Err(_) => return None,
+        };
+        let payload = frame.split_off(end);
+        Some(MessageRequestBinary {
+            header: header,
+            payload: payload,
+        })
+    }
+}
+
+/// Build a binary frame out of `header` and `payload`, laid out as
+/// described for `MessageRequestBinary`. Returns `None` if the header
+/// cannot be serialized or is longer than `MAX_BINARY_HEADER`.
+pub fn encode_binary<T: Serialize>(header: &T, payload: &[u8]) -> Option<Vec<u8>> {
+    let h = match serde_json::to_vec(header) {
+        Ok(h) => h,
+        Err(_) => return None,
+    };
+    if h.len() > MAX_BINARY_HEADER {
+        return None;
+    }
+    let mut out = Vec::with_capacity(2 + h.len() + payload.len());
+    out.push(h.len() as u8);
+    out.push((h.len() >> 8) as u8);
+    out.extend_from_slice(&h);
+    out.extend_from_slice(payload);
+    Some(out)
+}
+
+/// Errors reported back to clients in `MessageResponse::error`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseError {
+    /// The message is not valid JSON
+    ParseError,
+    /// The message is not a valid request object
+    InvalidRequest,
+    /// No handler is registered for the method
+    MethodNotFound,
+    /// The params are missing or invalid for the method
+    InvalidPayload,
+    /// The server failed to handle the request
+    InternalError,
+    /// The requested record or topic does not exist
+    NotFound,
+    /// The datastore failed to carry out the request
+    StoreError,
+    /// The router is not accepting commands
+    RouterUnavailable,
+    /// No reply came in time
+    Timeout,
+    /// The record to create already exists
+    Conflict,
+    /// The credentials are wrong, or the connection is not
+    /// authenticated as needed
+    Unauthorized,
+    /// The connection is sent more than it can take in right now
+    Overloaded,
+}
+
+impl ResponseError {
+    /// JSON-RPC error code. Application errors use the range reserved
+    /// for servers, from -32000 down.
+    pub fn code(&self) -> i64 {
+        match *self {
+            ResponseError::ParseError => -32700,
+            ResponseError::InvalidRequest => -32600,
+            ResponseError::MethodNotFound => -32601,
+            ResponseError::InvalidPayload => -32602,
+            ResponseError::InternalError => -32603,
+            ResponseError::NotFound => -32001,
+            ResponseError::StoreError => -32002,
+            ResponseError::RouterUnavailable => -32003,
+            ResponseError::Timeout => -32004,
+            ResponseError::Conflict => -32005,
+            ResponseError::Unauthorized => -32006,
+            ResponseError::Overloaded => -32007,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ResponseError::ParseError => "Parse error",
+            ResponseError::InvalidRequest => "Invalid Request",
+            ResponseError::MethodNotFound => "Method not found",
+            ResponseError::InvalidPayload => "Invalid params",
+            ResponseError::InternalError => "Internal error",
+            ResponseError::NotFound => "Not found",
+            ResponseError::StoreError => "Datastore error",
+            ResponseError::RouterUnavailable => "Router unavailable",
+            ResponseError::Timeout => "Request timed out",
+            ResponseError::Conflict => "Already exists",
+            ResponseError::Unauthorized => "Unauthorized",
+            ResponseError::Overloaded => "Overloaded",
+        }
+    }
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Error object of a `MessageResponse`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl From<ResponseError> for MessageError {
+    fn from(e: ResponseError) -> Self {
+        MessageError {
+            code: e.code(),
+            message: String::from(e.as_str()),
+        }
+    }
+}
+
+/// Response to a request. Exactly one of `result` and `error` is set.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessageResponse {
-    pub event: String,
-    pub payload: Option<String>,
-    pub error: Option<String>,
+    pub jsonrpc: String,
+    /// Id of the request, or `null` if it could not be read
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<MessageError>,
 }
 
 impl MessageResponse {
-    pub fn error(ev: &str, err: &str) -> Self {
+    pub fn error(id: Value, err: ResponseError) -> Self {
         MessageResponse {
-            event: String::from(ev),
-            payload: None,
-            error: Some(String::from(err)),
+            jsonrpc: String::from(JSONRPC_VERSION),
+            id: id,
+            result: None,
+            error: Some(MessageError::from(err)),
         }
     }
 
-    pub fn success(ev: &str, payload: String) -> Self {
+    pub fn success(id: Value, result: Value) -> Self {
         MessageResponse {
-            event: String::from(ev),
-            payload: Some(payload),
+            jsonrpc: String::from(JSONRPC_VERSION),
+            id: id,
+            result: Some(result),
             error: None,
         }
     }
 }
+
+/// Notification pushed by the server, such as a message delivered to
+/// a subscriber
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageNotification<T> {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: T,
+}
+
+impl<T> MessageNotification<T> {
+    pub fn new(method: &str, params: T) -> Self {
+        MessageNotification {
+            jsonrpc: String::from(JSONRPC_VERSION),
+            method: String::from(method),
+            params: params,
+        }
+    }
+}
@@ -1,4 +1,6 @@
 pub mod account_schema;
 pub mod config_schema;
+pub mod datastore_schema;
 pub mod topic_schema;
 pub mod message_schema;
+pub mod session_schema;
@@ -0,0 +1,15 @@
+/// Data structure for sessions
+
+/// What the server knows a connection as
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionHello {
+    /// Id of the connection, unique while the server runs
+    pub connection_id: u64,
+    /// Id the connection subscribes under, for as long as it lasts
+    pub session_id: String,
+    /// Id the messages the connection publishes carry
+    pub publisher_id: String,
+    /// Set if the connection authenticated, in which case the publisher
+    /// id is the id of the account or subject of the token
+    pub authenticated: bool,
+}
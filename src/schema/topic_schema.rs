@@ -1,22 +1,216 @@
 /// Data structure for topics
 
+use serde_json::Value;
+
 /// Create a topic
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TopicCreate {
     pub topic_id: String,
+    /// Number of messages to keep for replay. Overrides the router default.
+    pub history_size: Option<usize>,
+    /// Maximum age of messages kept for replay, in milliseconds.
+    /// Overrides the router default.
+    pub history_age_ms: Option<u64>,
+    /// Write history to disk so it survives restarts
+    pub durable: Option<bool>,
 }
 
 /// Subscribe to a topic
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TopicSubscribe {
     pub topic_id: String,
-    pub subscriber_id: String,
+    /// Name telling apart several subscriptions of the connection to
+    /// the same topic. The subscription is held under the session id of
+    /// the connection, followed by the name if given.
+    pub subscriber_id: Option<String>,
+    /// Replay kept messages starting at this sequence number
+    pub from_seq: Option<u64>,
+    /// Replay at most this many of the latest kept messages
+    pub last_n: Option<usize>,
+    /// Share the messages of the topic with the other subscribers in
+    /// this group, each message going to only one of them
+    pub group: Option<String>,
+    /// Deliver messages at least once: each message carries a
+    /// `delivery_id` to ack, and is delivered again until it is acked
+    pub ack: Option<bool>,
+    /// Only deliver messages whose JSON body matches this expression,
+    /// e.g. `temperature > 30 && site == "north"`
+    pub filter: Option<String>,
+}
+
+/// Acknowledge a message delivered at least once
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicAck {
+    /// Topic the message was published on
+    pub topic_id: String,
+    /// Name the subscription was made with, if any
+    pub subscriber_id: Option<String>,
+    pub delivery_id: u64,
+}
+
+/// Refer to a single topic, to delete or describe it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicKey {
+    pub topic_id: String,
+}
+
+/// List topics, in order of their ids
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicList {
+    /// Only list topics whose id starts with this
+    pub prefix: Option<String>,
+    /// Start after this topic id, as returned in `TopicListing::next`
+    pub after: Option<String>,
+    /// Maximum number of topics to return
+    pub limit: Option<usize>,
+}
+
+/// A page of topic ids
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicListing {
+    /// Topics on the page, leaving out those the connection may not
+    /// subscribe to
+    pub topics: Vec<String>,
+    /// Pass as `after` to get the next page. Not set on the last page.
+    pub next: Option<String>,
+}
+
+/// State of a topic
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicInfo {
+    pub topic_id: String,
+    /// Number of subscriptions on the topic itself
+    pub subscribers: usize,
+    /// Number of wildcard subscriptions matching the topic
+    pub pattern_subscribers: usize,
+    /// Messages published per second, measured over the last few seconds
+    pub message_rate: f64,
+    /// Sequence number of the last message published
+    pub last_seq: u64,
+    /// The retained message, as delivered to subscribers
+    pub retained: Option<Value>,
+    /// Number of kept messages and pending deliveries dropped for being
+    /// past their ttl
+    pub expired: u64,
+    /// Number of deliveries dropped because the subscriber's outgoing
+    /// queue was full
+    pub dropped: u64,
+    /// Creation time, in milliseconds since the Unix epoch
+    pub created_ms: u64,
+    pub durable: bool,
 }
 
 /// Send message to topic
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TopicPublish {
     pub topic_id: String,
-    pub publisher_id: String,
     pub message: String,
+    /// Keep the message as the topic's retained value. An empty retained
+    /// message clears the retained value.
+    pub retain: Option<bool>,
+    /// Correlation id of the request this message replies to. Replies
+    /// to an inbox without it are dropped.
+    pub correlation_id: Option<String>,
+    /// Milliseconds after which the message expires, and is no longer
+    /// kept nor delivered again. At most a year.
+    pub ttl_ms: Option<u64>,
+}
+
+/// Publish a message expecting a single reply, which is returned as the
+/// result of the call
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicRequest {
+    pub topic_id: String,
+    pub message: String,
+    /// Milliseconds to wait for the reply, at most a year. Overrides the
+    /// router default.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Reply to a `TopicRequest`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicReply {
+    pub correlation_id: String,
+    pub publisher_id: Option<String>,
+    pub message: String,
+}
+
+/// Send binary message to topic. These are the params of a request
+/// sent as a binary frame, whose payload is the message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicPublishBinary {
+    pub topic_id: String,
+    /// As for `TopicPublish`
+    pub retain: Option<bool>,
+    /// Milliseconds after which the message expires. At most a year.
+    pub ttl_ms: Option<u64>,
+}
+
+/// Message delivered to subscribers of a topic
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicMessage {
+    pub topic_id: String,
+    /// Sequence number of the message within its topic
+    pub seq: u64,
+    /// Session id of the connection that published the message
+    pub publisher_id: Option<String>,
+    /// Set if the message was published as the topic's retained value
+    pub retained: bool,
+    pub message: String,
+    /// Topic to publish the reply to, for requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Id to publish the reply with, for requests, or of the request
+    /// this message replies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Id to ack the message with, for subscriptions made with `ack`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<u64>,
+    /// Number of times the message was delivered, this one included,
+    /// for subscriptions made with `ack`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+}
+
+/// Params of a binary message delivered to subscribers of a topic. The
+/// message itself is the payload of the binary frame.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicMessageHeader {
+    pub topic_id: String,
+    /// Sequence number of the message within its topic
+    pub seq: u64,
+    /// Session id of the connection that published the message
+    pub publisher_id: Option<String>,
+    /// Set if the message was published as the topic's retained value
+    pub retained: bool,
+    /// Id to ack the message with, for subscriptions made with `ack`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<u64>,
+    /// Number of times the message was delivered, this one included,
+    /// for subscriptions made with `ack`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+}
+
+/// A message that was never acked, as published on the dead-letter
+/// topic of its topic
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicDeadLetter {
+    pub topic_id: String,
+    pub subscriber_id: String,
+    /// Number of times the message was delivered
+    pub attempts: u32,
+    /// The message, as it was delivered
+    pub message: Value,
+}
+
+/// Settings of a durable topic, as kept in the datastore
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicMeta {
+    pub topic_id: String,
+    pub history_size: usize,
+    pub history_age_ms: Option<u64>,
+    /// Creation time, in milliseconds since the Unix epoch
+    pub created_ms: Option<u64>,
 }
@@ -0,0 +1,86 @@
+//! Helpers shared by the tests of several modules
+
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, channel};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ws::{self, Handshake, Message, Sender};
+
+/// How long to wait for a frame that should come
+pub const RECV_TIMEOUT_MS: u64 = 2000;
+
+/// How long to wait for a frame that should not come
+pub const SILENCE_MS: u64 = 200;
+
+static SCRATCH_DIRS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A fresh, empty directory for the test `name`. Its path is unique to
+/// the call, so that neither concurrent tests nor test runs share one.
+pub fn scratch(name: &str) -> PathBuf {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let dir = env::temp_dir().join(format!("unicorn-{}-{}{:09}-{}",
+                                           name,
+                                           started.as_secs(),
+                                           started.subsec_nanos(),
+                                           SCRATCH_DIRS.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Server side of a test connection, handing over its sender once the
+/// handshake is done
+struct Opened {
+    out: Sender,
+    opened: mpsc::Sender<Sender>,
+}
+
+impl ws::Handler for Opened {
+    fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
+        let _ = self.opened.send(self.out.clone());
+        Ok(())
+    }
+}
+
+/// The sender of the server side of a connection to a local server,
+/// along with the frames its client gets
+pub fn connect() -> (Sender, mpsc::Receiver<Message>) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = format!("127.0.0.1:{}", port);
+
+    let (opened, out) = channel();
+    let listen = addr.clone();
+    thread::spawn(move || {
+        ws::listen(&listen[..], |out| {
+            Opened {
+                out: out,
+                opened: opened.clone(),
+            }
+        })
+    });
+    while TcpStream::connect(&addr[..]).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let (frames, received) = channel();
+    thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), |_| {
+            let frames = frames.clone();
+            move |m| {
+                let _ = frames.send(m);
+                Ok(())
+            }
+        })
+    });
+    (out.recv_timeout(Duration::from_millis(RECV_TIMEOUT_MS)).unwrap(), received)
+}
+
+/// Check that nothing comes on `frames` for a while
+pub fn is_silent(frames: &mpsc::Receiver<Message>) -> bool {
+    frames.recv_timeout(Duration::from_millis(SILENCE_MS)).is_err()
+}
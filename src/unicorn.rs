@@ -15,7 +15,12 @@ fn run_all(conf: unicorn::schema::config_schema::Config) {
     let kernel = thread::spawn(move || {
         unicorn::kernel::run(c1);
     });
+    let c2 = conf.clone();
+    let datastore = thread::spawn(move || {
+        unicorn::kernel::run_datastore(c2);
+    });
     let _ = kernel.join();
+    let _ = datastore.join();
 }
 
 fn init_logger(loglevel: &str) {
@@ -117,7 +122,7 @@ fn main() {
             match run.value_of("component") {
                 Some("api") => unicorn::kernel::run(conf),
                 Some("all") => run_all(conf),
-                Some("datastore") => unimplemented!(),
+                Some("datastore") => unicorn::kernel::run_datastore(conf),
                 Some(_) | None => {
                     println!("No components matched. See `unicorn help run` for available options.")
                 }
@@ -0,0 +1,9 @@
+//! Helpers shared across modules
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch
+pub fn now_millis() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
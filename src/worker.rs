@@ -0,0 +1,186 @@
+//! Pool of threads for the work of calls that would hold up the
+//! `WebSocket` event loop, such as waiting on the router or hashing a
+//! password.
+//!
+//! Handlers that answer through a `Responder` hand such work to the pool
+//! and return at once; the job sends the response when it is done. A
+//! bounded pool refuses jobs once enough of them are waiting, so that a
+//! burst of slow calls is turned away instead of piling up.
+
+use std::cmp;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use schema::message_schema::ResponseError;
+
+/// Threads of a pool made with `Workers::default`
+const DEFAULT_WORKERS: usize = 4;
+
+/// A job for the pool. `FnOnce` closures cannot be called out of a box
+/// directly, so jobs are called through this instead.
+trait Job: Send {
+    fn call(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn call(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+/// Why a job was not run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunError {
+    /// As many jobs as a bounded pool takes are already waiting
+    Full,
+    /// No thread of the pool is left
+    Stopped,
+}
+
+impl From<RunError> for ResponseError {
+    fn from(e: RunError) -> Self {
+        match e {
+            RunError::Full => ResponseError::Overloaded,
+            RunError::Stopped => ResponseError::InternalError,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Queue {
+    Unbounded(mpsc::Sender<Box<Job>>),
+    Bounded(mpsc::SyncSender<Box<Job>>),
+}
+
+/// Handle to a pool of threads. Clones feed the same threads, which stop
+/// once every handle is dropped.
+#[derive(Clone)]
+pub struct Workers {
+    jobs: Queue,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Workers::spawn(DEFAULT_WORKERS)
+    }
+}
+
+impl Workers {
+    /// Start a pool of `n` threads, at least one
+    pub fn spawn(n: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Box<Job>>();
+        Workers::start(n, rx);
+        Workers { jobs: Queue::Unbounded(tx) }
+    }
+
+    /// Start a pool of `n` threads, at least one, that refuses jobs
+    /// while `queue` of them are waiting for a thread
+    pub fn bounded(n: usize, queue: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Box<Job>>(queue);
+        Workers::start(n, rx);
+        Workers { jobs: Queue::Bounded(tx) }
+    }
+
+    fn start(n: usize, rx: mpsc::Receiver<Box<Job>>) {
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..cmp::max(n, 1) {
+            let rx = rx.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    loop {
+                        let job = match rx.lock() {
+                            Ok(r) => r.recv(),
+                            Err(p) => p.into_inner().recv(),
+                        };
+                        match job {
+                            Ok(j) => j.call(),
+                            Err(_) => break,
+                        }
+                    }
+                });
+            if let Err(e) = spawned {
+                error!("[worker] Unable to start worker {}: {}", i, e);
+            }
+        }
+    }
+
+    /// Run `job` on the next free thread of the pool. A job that is
+    /// refused is dropped without being run.
+    pub fn run<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<(), RunError> {
+        let sent = match self.jobs {
+            Queue::Unbounded(ref tx) => tx.send(Box::new(job)).map_err(|_| RunError::Stopped),
+            Queue::Bounded(ref tx) => {
+                tx.try_send(Box::new(job)).map_err(|e| {
+                    match e {
+                        mpsc::TrySendError::Full(_) => RunError::Full,
+                        mpsc::TrySendError::Disconnected(_) => RunError::Stopped,
+                    }
+                })
+            }
+        };
+        if sent == Err(RunError::Stopped) {
+            error!("[worker] No worker left to run the job");
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_every_job() {
+        let workers = Workers::spawn(3);
+        let (tx, rx) = mpsc::channel();
+        for i in 0..20 {
+            let tx = tx.clone();
+            workers.run(move || tx.send(i).unwrap()).unwrap();
+        }
+        let mut done: Vec<i32> = (0..20)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!(done, (0..20).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn keeps_running_after_a_job_blocks() {
+        let workers = Workers::spawn(2);
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+        workers.run(move || {
+                let _ = block_rx.recv();
+            })
+            .unwrap();
+        workers.run(move || tx.send(()).unwrap()).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        drop(block_tx);
+    }
+
+    #[test]
+    fn bounded_pools_refuse_jobs_once_full() {
+        let workers = Workers::bounded(1, 1);
+        let (started_tx, started) = mpsc::channel();
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+        workers.run(move || {
+                started_tx.send(()).unwrap();
+                let _ = block_rx.recv();
+            })
+            .unwrap();
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let waiting = tx.clone();
+        workers.run(move || waiting.send(1).unwrap()).unwrap();
+        assert_eq!(workers.run(move || tx.send(2).unwrap()), Err(RunError::Full));
+
+        drop(block_tx);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}